@@ -26,4 +26,66 @@ export class AdminApi {
 			},
 		});
 	}
+
+	async getLockouts(token: string): Promise<ApiTypes.Lockout[]> {
+		let r = await $fetch<ApiTypes.Lockout[]>(this.apiUrl + "/admin/lockouts", {
+			method: "GET",
+			headers: {
+				"Content-Type": "application/json",
+				Authorization: `Bearer ${token}`,
+			},
+		});
+		return r;
+	}
+
+	async unlock(token: string, lockout: ApiTypes.Lockout): Promise<void> {
+		await $fetch(
+			this.apiUrl +
+				`/admin/lockouts/${lockout.kind}/${encodeURIComponent(lockout.key)}`,
+			{
+				method: "DELETE",
+				headers: {
+					"Content-Type": "application/json",
+					Authorization: `Bearer ${token}`,
+				},
+			},
+		);
+	}
+
+	/** Lists the images the garbage collection would delete, without deleting them */
+	async getOrphanedImages(
+		token: string,
+		graceHours?: number,
+	): Promise<ApiTypes.OrphanCollection> {
+		let url = this.apiUrl + "/admin/images/orphans";
+		if (graceHours !== undefined) {
+			url += `?graceHours=${graceHours}`;
+		}
+		let r = await $fetch<ApiTypes.OrphanCollection>(url, {
+			method: "GET",
+			headers: {
+				"Content-Type": "application/json",
+				Authorization: `Bearer ${token}`,
+			},
+		});
+		return r;
+	}
+
+	async deleteOrphanedImages(
+		token: string,
+		graceHours?: number,
+	): Promise<ApiTypes.OrphanCollection> {
+		let url = this.apiUrl + "/admin/images/orphans";
+		if (graceHours !== undefined) {
+			url += `?graceHours=${graceHours}`;
+		}
+		let r = await $fetch<ApiTypes.OrphanCollection>(url, {
+			method: "DELETE",
+			headers: {
+				"Content-Type": "application/json",
+				Authorization: `Bearer ${token}`,
+			},
+		});
+		return r;
+	}
 }
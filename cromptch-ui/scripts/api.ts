@@ -2,6 +2,7 @@ import * as ApiTypes from "@/scripts/apiTypes";
 import { AdminApi } from "./adminApi";
 
 export const API_URL = "";
+const OIDC_STATE_KEY = "oidcState";
 
 export class Api {
 	protected apiUrl: string;
@@ -35,15 +36,38 @@ export class Api {
 		return `${this.apiUrl}/image/${id}`;
 	}
 
-	getImageThumbnailUrl(id: string): string {
-		return `${this.apiUrl}/image/thumbnail/${id}`;
+	getImageThumbnailUrl(id: string, size?: number, square?: boolean): string {
+		let params = new URLSearchParams();
+		if (size) {
+			params.set("size", size.toString());
+		}
+		if (square) {
+			params.set("crop", "square");
+		}
+		let query = params.toString();
+		return `${this.apiUrl}/image/thumbnail/${id}${query ? "?" + query : ""}`;
+	}
+
+	/** Every thumbnail size, for use as an `srcset` */
+	getImageSrcset(id: string, square?: boolean): string {
+		return ApiTypes.THUMBNAIL_SIZES.map(
+			(size) => `${this.getImageThumbnailUrl(id, size, square)} ${size}w`,
+		).join(", ");
+	}
+
+	async getImageMetadata(id: string): Promise<ApiTypes.ImageMetadata> {
+		return await $fetch<ApiTypes.ImageMetadata>(
+			`${this.apiUrl}/image/${id}/metadata`,
+		);
 	}
 
 	async login(
 		email: string,
 		password: string,
-	): Promise<ApiTypes.LoginResponse> {
-		let r = await $fetch<ApiTypes.LoginResponse>(this.apiUrl + "/user/login", {
+	): Promise<ApiTypes.LoginResponse | ApiTypes.TwoFactorChallenge> {
+		let r = await $fetch<
+			ApiTypes.LoginResponse | ApiTypes.TwoFactorChallenge
+		>(this.apiUrl + "/user/login", {
 			method: "POST",
 			headers: {
 				"Content-Type": "application/json",
@@ -53,6 +77,183 @@ export class Api {
 		return r;
 	}
 
+	async loginTwoFactor(
+		challenge: string,
+		code: string,
+	): Promise<ApiTypes.LoginResponse> {
+		let r = await $fetch<ApiTypes.LoginResponse>(
+			this.apiUrl + "/user/login/2fa",
+			{
+				method: "POST",
+				headers: {
+					"Content-Type": "application/json",
+				},
+				body: JSON.stringify({ challenge, code }),
+			},
+		);
+		return r;
+	}
+
+	async oidcAuthorize(): Promise<ApiTypes.OidcAuthorizeResponse> {
+		let r = await $fetch<ApiTypes.OidcAuthorizeResponse>(
+			this.apiUrl + "/user/oidc/authorize",
+		);
+		sessionStorage.setItem(OIDC_STATE_KEY, r.state);
+		return r;
+	}
+
+	async oidcLink(token: string): Promise<ApiTypes.OidcAuthorizeResponse> {
+		let r = await $fetch<ApiTypes.OidcAuthorizeResponse>(
+			this.apiUrl + "/user/oidc/link",
+			{
+				method: "POST",
+				headers: {
+					Authorization: `Bearer ${token}`,
+				},
+			},
+		);
+		sessionStorage.setItem(OIDC_STATE_KEY, r.state);
+		return r;
+	}
+
+	/** Whether a callback belongs to a login this browser started, which can only be checked once */
+	takeOidcState(state: string): boolean {
+		const expected = sessionStorage.getItem(OIDC_STATE_KEY);
+		sessionStorage.removeItem(OIDC_STATE_KEY);
+		return expected !== null && expected === state;
+	}
+
+	/** The token is needed to finish linking an account */
+	async oidcCallback(
+		code: string,
+		state: string,
+		token?: string | null,
+	): Promise<
+		| ApiTypes.LoginResponse
+		| ApiTypes.TwoFactorChallenge
+		| ApiTypes.OidcLinkedResponse
+	> {
+		let r = await $fetch<
+			| ApiTypes.LoginResponse
+			| ApiTypes.TwoFactorChallenge
+			| ApiTypes.OidcLinkedResponse
+		>(this.apiUrl + "/user/oidc/callback", {
+			method: "POST",
+			headers: {
+				"Content-Type": "application/json",
+				...(token ? { Authorization: `Bearer ${token}` } : {}),
+			},
+			body: JSON.stringify({ code, state }),
+		});
+		return r;
+	}
+
+	logout(token: string) {
+		return $fetch<string>(this.apiUrl + "/user/logout", {
+			method: "POST",
+			headers: {
+				Authorization: `Bearer ${token}`,
+			},
+		});
+	}
+
+	async getSessions(token: string): Promise<ApiTypes.Session[]> {
+		let r = await $fetch<ApiTypes.Session[]>(this.apiUrl + "/user/sessions", {
+			method: "GET",
+			headers: {
+				Authorization: `Bearer ${token}`,
+			},
+		});
+		return r;
+	}
+
+	revokeSession(token: string, id: string) {
+		return $fetch<string>(`${this.apiUrl}/user/sessions/${id}`, {
+			method: "DELETE",
+			headers: {
+				Authorization: `Bearer ${token}`,
+			},
+		});
+	}
+
+	async getApiTokens(token: string): Promise<ApiTypes.ApiToken[]> {
+		let r = await $fetch<ApiTypes.ApiToken[]>(this.apiUrl + "/user/tokens", {
+			method: "GET",
+			headers: {
+				Authorization: `Bearer ${token}`,
+			},
+		});
+		return r;
+	}
+
+	async createApiToken(
+		token: string,
+		name: string,
+		scopes: ApiTypes.ApiTokenScope[],
+		expiresInDays?: number,
+	): Promise<ApiTypes.CreateApiTokenResponse> {
+		let r = await $fetch<ApiTypes.CreateApiTokenResponse>(
+			this.apiUrl + "/user/tokens",
+			{
+				method: "POST",
+				headers: {
+					"Content-Type": "application/json",
+					Authorization: `Bearer ${token}`,
+				},
+				body: JSON.stringify({ name, scopes, expiresInDays }),
+			},
+		);
+		return r;
+	}
+
+	deleteApiToken(token: string, id: string) {
+		return $fetch<string>(`${this.apiUrl}/user/tokens/${id}`, {
+			method: "DELETE",
+			headers: {
+				Authorization: `Bearer ${token}`,
+			},
+		});
+	}
+
+	verifyEmail(token: string) {
+		return $fetch<string>(this.apiUrl + "/user/verify", {
+			method: "POST",
+			headers: {
+				"Content-Type": "application/json",
+			},
+			body: JSON.stringify({ token }),
+		});
+	}
+
+	resendVerification(token: string) {
+		return $fetch<string>(this.apiUrl + "/user/verify/resend", {
+			method: "POST",
+			headers: {
+				Authorization: `Bearer ${token}`,
+			},
+		});
+	}
+
+	forgotPassword(email: string) {
+		return $fetch<string>(this.apiUrl + "/user/password/forgot", {
+			method: "POST",
+			headers: {
+				"Content-Type": "application/json",
+			},
+			body: JSON.stringify({ email }),
+		});
+	}
+
+	resetPassword(token: string, password: string) {
+		return $fetch<string>(this.apiUrl + "/user/password/reset", {
+			method: "POST",
+			headers: {
+				"Content-Type": "application/json",
+			},
+			body: JSON.stringify({ token, password }),
+		});
+	}
+
 	async getSelfUser(token: string): Promise<ApiTypes.UserView> {
 		let r = await $fetch<ApiTypes.UserView>(this.apiUrl + "/user/self", {
 			method: "GET",
@@ -64,19 +265,86 @@ export class Api {
 		return r;
 	}
 
+	async updateSelf(
+		token: string,
+		update: ApiTypes.UpdateSelfRequest,
+	): Promise<ApiTypes.UserView> {
+		let r = await $fetch<ApiTypes.UserView>(this.apiUrl + "/user/self", {
+			method: "PUT",
+			headers: {
+				"Content-Type": "application/json",
+				Authorization: `Bearer ${token}`,
+			},
+			body: JSON.stringify(update),
+		});
+		return r;
+	}
+
+	changePassword(token: string, currentPassword: string, newPassword: string) {
+		return $fetch<string>(this.apiUrl + "/user/password/change", {
+			method: "POST",
+			headers: {
+				"Content-Type": "application/json",
+				Authorization: `Bearer ${token}`,
+			},
+			body: JSON.stringify({ currentPassword, newPassword }),
+		});
+	}
+
+	deleteAccount(
+		token: string,
+		password: string,
+		recipes: ApiTypes.RecipeHandling,
+		code?: string,
+	) {
+		return $fetch<string>(this.apiUrl + "/user/self", {
+			method: "DELETE",
+			headers: {
+				"Content-Type": "application/json",
+				Authorization: `Bearer ${token}`,
+			},
+			body: JSON.stringify({ password, code, recipes }),
+		});
+	}
+
+	async getUserProfile(username: string): Promise<ApiTypes.UserProfile> {
+		let r = await $fetch<ApiTypes.UserProfile>(
+			`${this.apiUrl}/user/${encodeURIComponent(username)}`,
+		);
+		return r;
+	}
+
+	async getUserRecipes(
+		username: string,
+		sortBy: ApiTypes.RecipeListSortTypes,
+		limit: number = 10,
+		cursor?: string,
+	): Promise<ApiTypes.RecipeListPage> {
+		let url = `${this.apiUrl}/user/${encodeURIComponent(
+			username,
+		)}/recipes?limit=${limit}&order=${sortBy}`;
+		if (cursor) {
+			url += `&cursor=${encodeURIComponent(cursor)}`;
+		}
+		let r = await $fetch<ApiTypes.RecipeListPage>(url);
+		return r;
+	}
+
 	async getRecipeList(
 		sortBy: ApiTypes.RecipeListSortTypes,
 		limit: number = 10,
-	): Promise<ApiTypes.RecipeMetadata[]> {
-		let r = await $fetch<ApiTypes.RecipeMetadata[]>(
-			`${this.apiUrl}/recipe/list?limit=${limit}&order=${sortBy}`,
-			{
-				method: "GET",
-				headers: {
-					"Content-Type": "application/json",
-				},
+		cursor?: string,
+	): Promise<ApiTypes.RecipeListPage> {
+		let url = `${this.apiUrl}/recipe/list?limit=${limit}&order=${sortBy}`;
+		if (cursor) {
+			url += `&cursor=${encodeURIComponent(cursor)}`;
+		}
+		let r = await $fetch<ApiTypes.RecipeListPage>(url, {
+			method: "GET",
+			headers: {
+				"Content-Type": "application/json",
 			},
-		);
+		});
 		return r;
 	}
 
@@ -93,6 +361,13 @@ export class Api {
 		return r;
 	}
 
+	async getRecipeJsonLd(id: string): Promise<object> {
+		let r = await $fetch<object>(`${this.apiUrl}/recipe/${id}/jsonld`, {
+			method: "GET",
+		});
+		return r;
+	}
+
 	async createRecipe(
 		body: ApiTypes.CreateRecipeRequest,
 		token: string,
@@ -126,4 +401,20 @@ export class Api {
 		});
 		return r;
 	}
+
+	async deleteImage(
+		id: string,
+		token: string,
+	): Promise<ApiTypes.DeleteImageResponse> {
+		let r = await $fetch<ApiTypes.DeleteImageResponse>(
+			`${this.apiUrl}/image/${id}`,
+			{
+				method: "DELETE",
+				headers: {
+					Authorization: `Bearer ${token}`,
+				},
+			},
+		);
+		return r;
+	}
 }
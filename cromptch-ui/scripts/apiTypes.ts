@@ -6,11 +6,92 @@ export interface LoginResponse {
 	token: string;
 }
 
+export interface TwoFactorChallenge {
+	twoFactorRequired: true;
+	challenge: string;
+}
+
+export interface OidcAuthorizeResponse {
+	authorizationUrl: string;
+	/** Has to come back unchanged to the callback page */
+	state: string;
+}
+
+export interface OidcLinkedResponse {
+	linked: true;
+}
+
 export interface UserView {
 	id: string;
 	username: string;
 	email: string;
 	isAdmin: boolean;
+	isVerified: boolean;
+	twoFactorEnabled: boolean;
+	twoFactorRequired: boolean;
+}
+
+export interface UpdateSelfRequest {
+	username?: string;
+	email?: string;
+	/** Needed to change the email address */
+	currentPassword?: string;
+	/** An empty string clears the display name */
+	displayName?: string;
+	/** An empty string clears the bio */
+	bio?: string;
+	/** `null` removes the avatar */
+	avatarId?: string | null;
+}
+
+export interface UserProfile {
+	username: string;
+	displayName?: string;
+	bio?: string;
+	avatarId?: string;
+	joinedAt: number;
+	recipeCount: number;
+}
+
+/** What happens to the recipes of a deleted account */
+export type RecipeHandling = "delete" | "transfer";
+
+export interface Lockout {
+	kind: "account" | "address";
+	/** The email or client address that is locked */
+	key: string;
+	userId?: string;
+	failures: number;
+	lastFailure: number;
+	lockedUntil: number;
+}
+
+export interface Session {
+	id: string;
+	createdAt: number;
+	lastUsed: number;
+	userAgent: string | null;
+	ipAddress: string | null;
+	current: boolean;
+}
+
+export type ApiTokenScope =
+	| "recipe:read"
+	| "recipe:write"
+	| "image:write"
+	| "admin";
+
+export interface ApiToken {
+	id: string;
+	name: string;
+	scopes: ApiTokenScope[];
+	createdAt: number;
+	lastUsed: number | null;
+	expiresAt: number | null;
+}
+
+export interface CreateApiTokenResponse extends ApiToken {
+	token: string;
 }
 
 export interface RecipeMetadata {
@@ -22,8 +103,17 @@ export interface RecipeMetadata {
 	timeEstimateActive?: number;
 	timeEstimateTotal?: number;
 	sourceUrl?: string;
+	servings?: number;
 	createdAt: number;
 	editedAt: number;
+	tags: string[];
+}
+
+export interface RecipeListPage {
+	recipes: RecipeMetadata[];
+	next?: string;
+	prev?: string;
+	total?: number;
 }
 
 export interface RecipeIngredient {
@@ -50,8 +140,10 @@ export interface CreateRecipeRequest {
 	timeEstimateActive?: number;
 	timeEstimateTotal?: number;
 	sourceUrl?: string;
+	servings?: number;
 	ingredients: Array<RecipeIngredient>;
 	steps: Array<RecipeStep>;
+	tags?: string[];
 }
 
 export interface CreateRecipeResponse {
@@ -61,10 +153,40 @@ export interface CreateRecipeResponse {
 export interface GetRecipeResponse {
 	recipe: Recipe;
 	author: string;
+	originalServings?: number;
 }
 
-export interface ImageUploadResponse {
+export interface ImageMetadata {
 	id: string;
+	/** Unknown for images uploaded before sizes were recorded */
+	width?: number;
+	height?: number;
+	/** Relative to the API root, like every URL here */
+	url: string;
+	/** Thumbnail URLs by variant name, like `256` or `256-square` */
+	variants: Record<string, string>;
+}
+
+export type ImageUploadResponse = ImageMetadata;
+
+export const THUMBNAIL_SIZES = [128, 256, 512, 1024];
+
+export interface OrphanedImage {
+	id: string;
+	owner?: string;
+	createdAt: number;
+}
+
+export interface OrphanCollection {
+	dryRun: boolean;
+	candidates: OrphanedImage[];
+	deleted: number;
+	failed: number;
+}
+
+export interface DeleteImageResponse {
+	/** Recipes that used the image as their own or a step's image */
+	affectedRecipes: string[];
 }
 
 export enum RecipeListSortTypes {
@@ -0,0 +1,43 @@
+CREATE TABLE recipe_revisions (
+	recipe_id UUID NOT NULL REFERENCES recipes(id) ON DELETE CASCADE,
+	num INTEGER NOT NULL,
+	editor UUID REFERENCES users(id) ON DELETE SET NULL,
+	created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	snapshot JSONB NOT NULL,
+	PRIMARY KEY (recipe_id, num)
+);
+
+-- Record the current state of every existing recipe as its first revision
+INSERT INTO recipe_revisions (recipe_id, num, editor, created_at, snapshot)
+SELECT r.id, 1, r.author, r.edited_at, jsonb_build_object(
+	'metadata', jsonb_build_object(
+		'id', r.id,
+		'title', r.title,
+		'description', r.description,
+		'author', r.author,
+		'imageId', r.image_id,
+		'timeEstimateActive', r.time_estimate_active::TEXT,
+		'timeEstimateTotal', r.time_estimate_total::TEXT,
+		'sourceUrl', r.source_url,
+		'createdAt', EXTRACT(EPOCH FROM r.created_at)::BIGINT,
+		'editedAt', EXTRACT(EPOCH FROM r.edited_at)::BIGINT
+	),
+	'ingredients', COALESCE((
+		SELECT jsonb_agg(jsonb_build_object(
+			'quantity', i.quantity::TEXT,
+			'unit', i.unit,
+			'name', i.name
+		) ORDER BY i.num)
+		FROM recipe_ingredients i
+		WHERE i.recipe_id = r.id
+	), '[]'::JSONB),
+	'steps', COALESCE((
+		SELECT jsonb_agg(jsonb_build_object(
+			'description', s.description,
+			'imageId', s.image_id
+		) ORDER BY s.num)
+		FROM recipe_steps s
+		WHERE s.recipe_id = r.id
+	), '[]'::JSONB)
+)
+FROM recipes r;
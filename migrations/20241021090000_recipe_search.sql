@@ -0,0 +1,26 @@
+ALTER TABLE recipes
+ADD COLUMN search_document TSVECTOR NOT NULL DEFAULT ''::TSVECTOR;
+
+-- Title is weighted A, ingredient names B, description C and step text D,
+-- so the ranking weights can be chosen per query
+CREATE FUNCTION recipe_search_document(recipe UUID) RETURNS TSVECTOR AS $$
+	SELECT
+		setweight(to_tsvector('english', r.title), 'A') ||
+		setweight(to_tsvector('english', COALESCE((
+			SELECT string_agg(i.name, ' ' ORDER BY i.num)
+			FROM recipe_ingredients i
+			WHERE i.recipe_id = r.id
+		), '')), 'B') ||
+		setweight(to_tsvector('english', r.description), 'C') ||
+		setweight(to_tsvector('english', COALESCE((
+			SELECT string_agg(s.description, ' ' ORDER BY s.num)
+			FROM recipe_steps s
+			WHERE s.recipe_id = r.id
+		), '')), 'D')
+	FROM recipes r
+	WHERE r.id = recipe
+$$ LANGUAGE SQL STABLE;
+
+UPDATE recipes SET search_document = recipe_search_document(id);
+
+CREATE INDEX recipes_search_document_idx ON recipes USING GIN (search_document);
@@ -0,0 +1,29 @@
+CREATE TABLE tags (
+	id UUID PRIMARY KEY UNIQUE DEFAULT uuid_generate_v4(),
+	name TEXT NOT NULL UNIQUE
+);
+
+CREATE TABLE recipe_tags (
+	recipe_id UUID NOT NULL REFERENCES recipes(id) ON DELETE CASCADE,
+	tag_id UUID NOT NULL REFERENCES tags(id) ON DELETE CASCADE,
+	PRIMARY KEY (recipe_id, tag_id)
+);
+
+CREATE INDEX recipe_tags_tag_id_idx ON recipe_tags (tag_id);
+
+-- Whether a recipe has all (or any) of the wanted tags. An empty list matches everything.
+CREATE FUNCTION recipe_matches_tags(recipe UUID, wanted TEXT[], match_all BOOLEAN) RETURNS BOOLEAN AS $$
+	SELECT CASE
+		WHEN cardinality(wanted) = 0 THEN TRUE
+		WHEN match_all THEN (
+			SELECT COUNT(*)
+			FROM recipe_tags rt INNER JOIN tags t ON t.id = rt.tag_id
+			WHERE rt.recipe_id = recipe AND t.name = ANY(wanted)
+		) = (SELECT COUNT(DISTINCT w) FROM unnest(wanted) w)
+		ELSE EXISTS (
+			SELECT 1
+			FROM recipe_tags rt INNER JOIN tags t ON t.id = rt.tag_id
+			WHERE rt.recipe_id = recipe AND t.name = ANY(wanted)
+		)
+	END
+$$ LANGUAGE SQL STABLE;
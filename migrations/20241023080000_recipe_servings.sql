@@ -0,0 +1,2 @@
+ALTER TABLE recipes
+ADD COLUMN servings INTEGER CHECK (servings > 0);
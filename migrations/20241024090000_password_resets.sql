@@ -0,0 +1,10 @@
+-- Only a SHA-256 digest of each reset token is stored, the token itself is only ever sent by mail
+CREATE TABLE password_resets (
+	token_hash CHAR(64) PRIMARY KEY,
+	user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+	created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	expires_at TIMESTAMP NOT NULL,
+	used_at TIMESTAMP
+);
+
+CREATE INDEX password_resets_user_id ON password_resets(user_id);
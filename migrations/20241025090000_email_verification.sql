@@ -0,0 +1,14 @@
+ALTER TABLE users
+ADD COLUMN verified_at TIMESTAMP;
+
+-- Accounts from before verification existed keep working as they did
+UPDATE users SET verified_at = NOW();
+
+CREATE TABLE email_verifications (
+	token_hash CHAR(64) PRIMARY KEY,
+	user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+	created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX email_verifications_user_id ON email_verifications(user_id);
@@ -0,0 +1,7 @@
+-- Sessions get an id of their own, so they can be revoked without knowing the token
+ALTER TABLE user_tokens
+ADD COLUMN id UUID NOT NULL UNIQUE DEFAULT uuid_generate_v4(),
+ADD COLUMN user_agent TEXT,
+ADD COLUMN ip_address TEXT;
+
+CREATE INDEX user_tokens_user_id ON user_tokens(user_id);
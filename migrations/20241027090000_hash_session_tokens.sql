@@ -0,0 +1,6 @@
+-- Session tokens are stored as hex encoded SHA-256 digests. Existing tokens are
+-- rehashed in place, so nobody gets logged out.
+ALTER TABLE user_tokens RENAME COLUMN token TO token_hash;
+
+UPDATE user_tokens
+SET token_hash = encode(sha256(convert_to(token_hash, 'UTF8')), 'hex');
@@ -0,0 +1,13 @@
+-- Long-lived tokens for scripts, limited to a set of scopes
+CREATE TABLE api_tokens (
+	id UUID PRIMARY KEY DEFAULT uuid_generate_v4(),
+	user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+	name TEXT NOT NULL,
+	token_hash CHAR(64) NOT NULL UNIQUE,
+	scopes TEXT[] NOT NULL,
+	created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	last_used TIMESTAMP,
+	expires_at TIMESTAMP
+);
+
+CREATE INDEX api_tokens_user_id ON api_tokens(user_id);
@@ -0,0 +1,21 @@
+-- TOTP secrets are set on enrolment, and only take effect once confirmed with a first code
+ALTER TABLE users
+ADD COLUMN totp_secret TEXT,
+ADD COLUMN totp_enabled_at TIMESTAMP,
+-- Time step of the last accepted code, so codes cannot be used twice
+ADD COLUMN totp_last_step BIGINT;
+
+CREATE TABLE recovery_codes (
+	user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+	code_hash CHAR(64) NOT NULL,
+	used_at TIMESTAMP,
+	PRIMARY KEY (user_id, code_hash)
+);
+
+-- Logins waiting for their second factor
+CREATE TABLE login_challenges (
+	token_hash CHAR(64) PRIMARY KEY,
+	user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+	attempts INTEGER NOT NULL DEFAULT 0,
+	expires_at TIMESTAMP NOT NULL
+);
@@ -0,0 +1,20 @@
+-- Accounts at external identity providers, identified by issuer and subject
+CREATE TABLE oidc_identities (
+	provider TEXT NOT NULL,
+	subject TEXT NOT NULL,
+	user_id UUID NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+	created_at TIMESTAMP NOT NULL DEFAULT NOW(),
+	PRIMARY KEY (provider, subject)
+);
+
+CREATE INDEX oidc_identities_user_id ON oidc_identities(user_id);
+
+-- Logins that have been sent to the provider and not come back yet
+CREATE TABLE oidc_logins (
+	state_hash CHAR(64) PRIMARY KEY,
+	code_verifier TEXT NOT NULL,
+	nonce TEXT NOT NULL,
+	-- Set when a signed in user is linking an identity to their account
+	link_user_id UUID REFERENCES users(id) ON DELETE CASCADE,
+	expires_at TIMESTAMP NOT NULL
+);
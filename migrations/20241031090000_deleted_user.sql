@@ -0,0 +1,12 @@
+-- Recipes of deleted accounts can be handed over to this placeholder instead of being deleted.
+-- Its password is not a valid hash, so nobody can log in as it.
+-- Accounts that already took its username or email are renamed first, as both are unique.
+UPDATE users
+SET username = username || '-' || id
+WHERE username = '[deleted]' AND id <> '00000000-0000-0000-0000-000000000000';
+UPDATE users
+SET email = id || '.' || email
+WHERE email = 'deleted@invalid' AND id <> '00000000-0000-0000-0000-000000000000';
+INSERT INTO users (id, username, email, password, is_admin, verified_at)
+VALUES ('00000000-0000-0000-0000-000000000000', '[deleted]', 'deleted@invalid', '', FALSE, NOW())
+ON CONFLICT DO NOTHING;
@@ -0,0 +1,11 @@
+ALTER TABLE users
+ADD COLUMN display_name TEXT,
+ADD COLUMN bio TEXT,
+ADD COLUMN avatar_id UUID REFERENCES images(id) ON DELETE SET NULL,
+ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT NOW();
+
+-- Sign up dates were not recorded before, the first recipe is the best guess there is
+UPDATE users u
+SET created_at = LEAST(u.created_at, (SELECT MIN(r.created_at) FROM recipes r WHERE r.author = u.id));
+
+CREATE INDEX recipes_author ON recipes(author);
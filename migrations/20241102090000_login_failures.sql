@@ -0,0 +1,9 @@
+-- Failed logins per account (keyed by email, whether or not the account exists) and per client address
+CREATE TABLE login_failures (
+	kind TEXT NOT NULL,
+	key TEXT NOT NULL,
+	failures INTEGER NOT NULL DEFAULT 0,
+	last_failure TIMESTAMP NOT NULL DEFAULT NOW(),
+	locked_until TIMESTAMP,
+	PRIMARY KEY (kind, key)
+);
@@ -0,0 +1,4 @@
+-- Needed to give new uploads a grace period before they count as abandoned.
+-- Existing images get theirs starting now.
+ALTER TABLE images
+ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT NOW();
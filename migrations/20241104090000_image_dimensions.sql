@@ -0,0 +1,5 @@
+-- Sizes of originals, so clients can lay out responsive images before loading them.
+-- Images uploaded before this are left without.
+ALTER TABLE images
+ADD COLUMN width INTEGER,
+ADD COLUMN height INTEGER;
@@ -0,0 +1,2 @@
+-- Orphan collection looks up images in the snapshots of past revisions
+CREATE INDEX recipe_revisions_snapshot_idx ON recipe_revisions USING GIN (snapshot jsonb_path_ops);
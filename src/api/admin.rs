@@ -1,17 +1,24 @@
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
 use axum::{
-	extract::{Path, State},
-	routing::{delete, get},
+	extract::{Path, Query, State},
+	routing::{delete, get, post},
 	Json, Router,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use tracing::info;
 use uuid::Uuid;
 
 use crate::{
-	error::AppResult,
-	models::{admin::Admin, recipe::Recipe, user::User},
+	error::{AppError, AppResult},
+	models::{
+		admin::Admin,
+		image::{Image, OrphanCollection},
+		login_failure::{Lockout, LoginFailures},
+		recipe::Recipe,
+		tag::Tag,
+		user::User,
+	},
 	AppState,
 };
 
@@ -19,6 +26,14 @@ pub fn admin_router(state: Arc<AppState>) -> Router {
 	Router::new()
 		.route("/api/admin/users", get(get_users))
 		.route("/api/admin/recipe/:id", delete(delete_recipe))
+		.route("/api/admin/tag/:id", delete(delete_tag).put(rename_tag))
+		.route("/api/admin/tag/:id/merge", post(merge_tag))
+		.route(
+			"/api/admin/images/orphans",
+			get(list_orphaned_images).delete(delete_orphaned_images),
+		)
+		.route("/api/admin/lockouts", get(list_lockouts))
+		.route("/api/admin/lockouts/:kind/:key", delete(unlock))
 		.with_state(state)
 }
 
@@ -29,6 +44,7 @@ pub struct UserViewResponseEntry {
 	pub username: String,
 	pub email: String,
 	pub is_admin: bool,
+	pub is_verified: bool,
 }
 
 async fn get_users(
@@ -44,6 +60,7 @@ async fn get_users(
 				username: u.username,
 				email: u.email,
 				is_admin: u.is_admin,
+				is_verified: u.verified_at.is_some(),
 			})
 			.collect(),
 	))
@@ -62,3 +79,117 @@ async fn delete_recipe(
 	recipe.delete(&state.pool).await?;
 	Ok(())
 }
+
+#[derive(Deserialize)]
+pub struct RenameTagRequest {
+	pub name: String,
+}
+
+async fn rename_tag(
+	State(state): State<Arc<AppState>>,
+	admin: Admin,
+	Path(id): Path<Uuid>,
+	Json(RenameTagRequest { name }): Json<RenameTagRequest>,
+) -> AppResult<Json<Tag>> {
+	Tag::validate(&name)?;
+	let tag = Tag::from_uuid(&state.pool, &id).await?;
+	let renamed = tag.rename(&state.pool, &name).await?;
+	info!(
+		"User {} renamed tag {} to {}",
+		admin.user.id, tag.name, renamed.name
+	);
+	Ok(Json(renamed))
+}
+
+#[derive(Deserialize)]
+pub struct MergeTagRequest {
+	pub into: Uuid,
+}
+
+async fn merge_tag(
+	State(state): State<Arc<AppState>>,
+	admin: Admin,
+	Path(id): Path<Uuid>,
+	Json(MergeTagRequest { into }): Json<MergeTagRequest>,
+) -> AppResult<Json<Tag>> {
+	let tag = Tag::from_uuid(&state.pool, &id).await?;
+	let target = Tag::from_uuid(&state.pool, &into).await?;
+	let merged = tag.merge_into(&state.pool, &target).await?;
+	info!(
+		"User {} merged tag {} into {}",
+		admin.user.id, tag.name, merged.name
+	);
+	Ok(Json(merged))
+}
+
+async fn delete_tag(
+	State(state): State<Arc<AppState>>,
+	admin: Admin,
+	Path(id): Path<Uuid>,
+) -> AppResult<()> {
+	let tag = Tag::from_uuid(&state.pool, &id).await?;
+	info!("User {} deleted tag {}", admin.user.id, tag.name);
+	tag.delete(&state.pool).await?;
+	Ok(())
+}
+
+async fn list_lockouts(
+	State(state): State<Arc<AppState>>,
+	_: Admin,
+) -> AppResult<Json<Vec<Lockout>>> {
+	Ok(Json(LoginFailures::list_locked(&state.pool).await?))
+}
+
+async fn unlock(
+	State(state): State<Arc<AppState>>,
+	admin: Admin,
+	Path((kind, key)): Path<(String, String)>,
+) -> AppResult<()> {
+	if !LoginFailures::unlock(&state.pool, &kind, &key).await? {
+		return Err(AppError::not_found("Lockout not found"));
+	}
+	info!(
+		"User {} lifted the {} lockout of {}",
+		admin.user.id, kind, key
+	);
+	Ok(())
+}
+
+/// Runs the image garbage collection, with the grace period from the `graceHours` parameter if given
+async fn collect_orphaned_images(
+	state: &AppState,
+	params: &HashMap<String, String>,
+	dry_run: bool,
+) -> AppResult<OrphanCollection> {
+	let grace_hours = match params.get("graceHours") {
+		Some(h) => h
+			.parse::<i32>()
+			.ok()
+			.filter(|h| *h >= 0)
+			.ok_or(AppError::bad_request("Invalid grace period"))?,
+		None => state.secrets.image_gc_grace_hours,
+	};
+	Image::collect_orphans(&state.pool, &*state.images, grace_hours, dry_run).await
+}
+
+/// A dry run of the image garbage collection
+async fn list_orphaned_images(
+	State(state): State<Arc<AppState>>,
+	_: Admin,
+	Query(params): Query<HashMap<String, String>>,
+) -> AppResult<Json<OrphanCollection>> {
+	Ok(Json(collect_orphaned_images(&state, &params, true).await?))
+}
+
+async fn delete_orphaned_images(
+	State(state): State<Arc<AppState>>,
+	admin: Admin,
+	Query(params): Query<HashMap<String, String>>,
+) -> AppResult<Json<OrphanCollection>> {
+	let collection = collect_orphaned_images(&state, &params, false).await?;
+	info!(
+		"User {} deleted {} orphaned images, {} failed",
+		admin.user.id, collection.deleted, collection.failed
+	);
+	Ok(Json(collection))
+}
@@ -1,70 +1,141 @@
 use std::sync::Arc;
 
 use axum::{
-	extract::{Multipart, Path, State},
-	http,
-	response::IntoResponse,
+	body::StreamBody,
+	extract::{Multipart, Path, Query, State},
+	http::{header, HeaderMap, HeaderValue, StatusCode},
+	response::{IntoResponse, Response},
 	routing::{get, post},
 	Json, Router,
 };
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use tracing::info;
 use uuid::Uuid;
 
 use crate::{
 	error::{AppError, AppResult},
-	external::{
+	external::image::{ImageData, ThumbnailVariant},
+	models::{
 		self,
-		image::{get_image_bytes, get_thumbnail_bytes},
+		image::{Image, ImageMetadata},
+		user::User,
+		verified_user::VerifiedUser,
 	},
-	models::{self, user::User},
 	AppState,
 };
 
 pub fn image_router(state: Arc<AppState>) -> Router {
-	if state.secrets.pictrs_url.is_empty() {
-		return Router::new();
-	}
 	Router::new()
-		.route("/api/image/:id", get(get_image))
+		.route("/api/image/:id", get(get_image).delete(delete_image))
+		.route("/api/image/:id/metadata", get(get_metadata))
 		.route("/api/image/thumbnail/:id", get(get_thumbnail))
 		.route("/api/image", post(upload_image))
 		.with_state(state)
 }
 
+/// Images never change once uploaded, so they can be cached for as long as browsers allow
+const IMAGE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
 async fn get_image(
 	State(state): State<Arc<AppState>>,
 	Path(id): Path<Uuid>,
-) -> AppResult<impl IntoResponse> {
-	let image_bytes = get_image_bytes(&state.secrets.pictrs_url, &id).await?;
-	Ok((
-		http::StatusCode::OK,
-		[(http::header::CONTENT_TYPE, "image/webp")],
-		image_bytes,
-	))
+	headers: HeaderMap,
+) -> AppResult<Response> {
+	// Clients may only keep using their copy of images that still exist
+	Image::from_uuid(&state.pool, &id).await?;
+	let etag = format!("\"{}\"", id);
+	if matches_etag(&headers, &etag) {
+		return Ok(not_modified(&etag));
+	}
+	let image = state.images.original(&id).await?;
+	Ok(image_response(image, &etag))
+}
+
+#[derive(Debug, Deserialize)]
+struct ThumbnailQuery {
+	size: Option<u32>,
+	crop: Option<ThumbnailCrop>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum ThumbnailCrop {
+	Square,
 }
 
 async fn get_thumbnail(
 	State(state): State<Arc<AppState>>,
 	Path(id): Path<Uuid>,
-) -> AppResult<impl IntoResponse> {
-	let image_bytes = get_thumbnail_bytes(&state.secrets.pictrs_url, &id).await?;
-	Ok((
-		http::StatusCode::OK,
-		[(http::header::CONTENT_TYPE, "image/webp")],
-		image_bytes,
-	))
+	Query(query): Query<ThumbnailQuery>,
+	headers: HeaderMap,
+) -> AppResult<Response> {
+	let variant = ThumbnailVariant::new(
+		query.size.unwrap_or(ThumbnailVariant::default().size()),
+		matches!(query.crop, Some(ThumbnailCrop::Square)),
+	)?;
+	Image::from_uuid(&state.pool, &id).await?;
+	let etag = format!("\"{}-thumbnail-{}\"", id, variant.name());
+	if matches_etag(&headers, &etag) {
+		return Ok(not_modified(&etag));
+	}
+	let image = state.images.thumbnail(&id, variant).await?;
+	Ok(image_response(image, &etag))
 }
 
-#[derive(Debug, Serialize)]
-struct UploadImageResponse {
-	id: Uuid,
+async fn get_metadata(
+	State(state): State<Arc<AppState>>,
+	Path(id): Path<Uuid>,
+) -> AppResult<Json<ImageMetadata>> {
+	let image = Image::from_uuid(&state.pool, &id).await?;
+	Ok(Json(image.metadata()))
+}
+
+/// Whether the client already has the image, going by `If-None-Match`
+fn matches_etag(headers: &HeaderMap, etag: &str) -> bool {
+	headers
+		.get_all(header::IF_NONE_MATCH)
+		.iter()
+		.filter_map(|value| value.to_str().ok())
+		.flat_map(|value| value.split(','))
+		.map(|tag| tag.trim())
+		.any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+fn not_modified(etag: &str) -> Response {
+	(
+		StatusCode::NOT_MODIFIED,
+		[
+			(header::ETAG, etag.to_string()),
+			(header::CACHE_CONTROL, IMAGE_CACHE_CONTROL.to_string()),
+		],
+	)
+		.into_response()
+}
+
+fn image_response(image: ImageData, etag: &str) -> Response {
+	let mut response = (
+		StatusCode::OK,
+		[
+			(header::CONTENT_TYPE, image.content_type),
+			(header::ETAG, etag.to_string()),
+			(header::CACHE_CONTROL, IMAGE_CACHE_CONTROL.to_string()),
+		],
+		StreamBody::new(image.body),
+	)
+		.into_response();
+	if let Some(length) = image.content_length {
+		response
+			.headers_mut()
+			.insert(header::CONTENT_LENGTH, HeaderValue::from(length));
+	}
+	response
 }
 
 async fn upload_image(
 	State(state): State<Arc<AppState>>,
-	user: User,
+	VerifiedUser { user }: VerifiedUser,
 	mut multipart: Multipart,
-) -> AppResult<Json<UploadImageResponse>> {
+) -> AppResult<Json<ImageMetadata>> {
 	let field = multipart
 		.next_field()
 		.await
@@ -85,27 +156,40 @@ async fn upload_image(
 		.await
 		.map_err(|_| AppError::bad_request("Invalid image file"))?;
 
-	let uploaded =
-		external::image::upload_image(&state.secrets.pictrs_url, &form_bytes, &file_name)
-			.await
-			.map_err(|_| AppError::bad_request("Image upload failed"))?;
-	if uploaded.files.len() != 1 {
-		return Err(AppError::internal("Image upload failed"));
-	}
-	let uploaded = uploaded.files.first().unwrap();
-	let uploaded_id = Uuid::parse_str(
-		uploaded
-			.file
-			.split_once(".")
-			.ok_or(AppError::internal("Image uploaded failed"))?
-			.0,
+	let inserted = models::image::Image::upload(
+		&state.pool,
+		&*state.images,
+		&form_bytes,
+		&file_name,
+		&user.id,
 	)
-	.map_err(|_| AppError::internal("Image upload failed"))?;
-	let delete_token = Uuid::parse_str(&uploaded.delete_token)
-		.map_err(|_| AppError::internal("Image upload failed"))?;
+	.await?;
 
-	let inserted =
-		models::image::Image::create(&state.pool, &uploaded_id, &delete_token, &user.id).await?;
+	Ok(Json(inserted.metadata()))
+}
 
-	Ok(Json(UploadImageResponse { id: inserted.id }))
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeleteImageResponse {
+	/// Recipes that used the image as their own or a step's image
+	affected_recipes: Vec<Uuid>,
+}
+
+async fn delete_image(
+	State(state): State<Arc<AppState>>,
+	user: User,
+	Path(id): Path<Uuid>,
+) -> AppResult<Json<DeleteImageResponse>> {
+	let image = Image::from_uuid(&state.pool, &id).await?;
+	if image.owner != Some(user.id) && !user.is_admin {
+		return Err(AppError::forbidden("Only the owner can delete this image"));
+	}
+	let affected_recipes = image.delete(&state.pool, &*state.images).await?;
+	info!(
+		"User {} deleted image {}, affecting {} recipes",
+		user.id,
+		id,
+		affected_recipes.len()
+	);
+	Ok(Json(DeleteImageResponse { affected_recipes }))
 }
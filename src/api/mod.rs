@@ -1,4 +1,5 @@
 pub mod admin;
 pub mod image;
+pub mod oidc;
 pub mod recipe;
 pub mod user;
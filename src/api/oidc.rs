@@ -0,0 +1,148 @@
+use std::{net::SocketAddr, sync::Arc};
+
+use axum::{
+	extract::{ConnectInfo, State},
+	http::HeaderMap,
+	routing::{get, post},
+	Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+	api::user::{complete_login, LoginResponse},
+	error::{AppError, AppResult},
+	external::oidc::{self, OidcConfig},
+	models::{
+		oidc::{OidcIdentity, OidcLogin},
+		user::User,
+	},
+	AppState,
+};
+
+pub fn oidc_router(state: Arc<AppState>) -> Router {
+	if state.secrets.oidc.is_none() {
+		return Router::new();
+	}
+	Router::new()
+		.route("/api/user/oidc/authorize", get(authorize))
+		.route("/api/user/oidc/link", post(link))
+		.route("/api/user/oidc/callback", post(callback))
+		.with_state(state)
+}
+
+fn config(state: &AppState) -> &OidcConfig {
+	// The routes only exist if there is a configuration
+	state.secrets.oidc.as_ref().unwrap()
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AuthorizeResponse {
+	/// Where to send the user to log in at the identity provider
+	authorization_url: String,
+	/// Kept by the client to check that the callback belongs to a login it started
+	state: String,
+}
+
+async fn start(state: &AppState, link_user: Option<&User>) -> AppResult<AuthorizeResponse> {
+	let (login_state, login) = OidcLogin::create(&state.pool, link_user.map(|u| &u.id)).await?;
+	let config = config(state);
+	let metadata = state.oidc_discovery.get(&config.issuer).await?;
+	let authorization_url = oidc::authorization_url(
+		config,
+		&metadata,
+		&login_state,
+		&login.nonce,
+		&login.code_verifier,
+	)?;
+	Ok(AuthorizeResponse {
+		authorization_url,
+		state: login_state,
+	})
+}
+
+async fn authorize(State(state): State<Arc<AppState>>) -> AppResult<Json<AuthorizeResponse>> {
+	Ok(Json(start(&state, None).await?))
+}
+
+async fn link(
+	State(state): State<Arc<AppState>>,
+	user: User,
+) -> AppResult<Json<AuthorizeResponse>> {
+	Ok(Json(start(&state, Some(&user)).await?))
+}
+
+#[derive(Deserialize)]
+pub struct CallbackRequest {
+	pub code: String,
+	pub state: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkedResponse {
+	pub linked: bool,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum CallbackResponse {
+	Login(LoginResponse),
+	Linked(LinkedResponse),
+}
+
+async fn callback(
+	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	user: Option<User>,
+	Json(CallbackRequest {
+		code,
+		state: login_state,
+	}): Json<CallbackRequest>,
+) -> AppResult<Json<CallbackResponse>> {
+	let config = config(&state);
+	let login = OidcLogin::take(&state.pool, &login_state).await?;
+	// Otherwise someone could start linking their account and get another user to finish it,
+	// linking that user's identity to their account
+	if login.link_user_id.is_some() && login.link_user_id != user.map(|u| u.id) {
+		return Err(AppError::forbidden(
+			"Linking has to be finished by the account that started it",
+		));
+	}
+	let metadata = state.oidc_discovery.get(&config.issuer).await?;
+	let claims =
+		oidc::exchange_code(config, &metadata, &code, &login.code_verifier, &login.nonce).await?;
+
+	let mut tx = state
+		.pool
+		.begin()
+		.await
+		.map_err(|_| AppError::internal("Failed to start transaction"))?;
+	if let Some(user_id) = login.link_user_id {
+		OidcIdentity::link(&mut tx, &claims, &user_id).await?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		return Ok(Json(CallbackResponse::Linked(LinkedResponse {
+			linked: true,
+		})));
+	}
+
+	let user = match OidcIdentity::find_user(&state.pool, &claims).await? {
+		Some(user) => user,
+		None if config.auto_provision => {
+			// The account must not outlive a failed link, or the next login could not provision it
+			let user = OidcIdentity::provision(&mut tx, &claims).await?;
+			tx.commit()
+				.await
+				.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+			user
+		}
+		None => return Err(AppError::forbidden("No account is linked to this identity")),
+	};
+	info!("User {} logged in through OIDC", user.id);
+	let response = complete_login(&state, user, &headers, &peer).await?;
+	Ok(Json(CallbackResponse::Login(response)))
+}
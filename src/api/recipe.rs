@@ -1,7 +1,10 @@
 use std::{collections::HashMap, sync::Arc};
 
 use axum::{
-	extract::{Path, Query, State},
+	body::{Bytes, StreamBody},
+	extract::{DefaultBodyLimit, Path, Query, State},
+	http::{header, HeaderMap},
+	response::IntoResponse,
 	routing::{get, post},
 	Json, Router,
 };
@@ -14,8 +17,18 @@ use uuid::Uuid;
 use crate::{
 	error::{AppError, AppResult},
 	models::{
-		recipe::{Recipe, RecipeCreation, RecipeListSort, RecipeMetadata},
+		archive::{self, ArchiveExport, ArchiveImport},
+		image::Image,
+		recipe::{
+			Recipe, RecipeCreation, RecipeCursor, RecipeListFilter, RecipeListSort, RecipePage,
+			RecipeSearchResult, RecipeSearchWeights,
+		},
+		revision::{RecipeDiff, RecipeRevision, RecipeRevisionBrief},
+		schema_org::{self, RecipeImport},
+		tag::Tag,
+		unit::UnitSystem,
 		user::User,
+		verified_user::VerifiedUser,
 	},
 	AppState,
 };
@@ -23,8 +36,28 @@ use crate::{
 pub fn recipe_router(state: Arc<AppState>) -> Router {
 	Router::new()
 		.route("/api/recipe/create", post(create_recipe))
-		.route("/api/recipe/:id", get(get_recipe))
+		.route("/api/recipe/import", post(import_recipe))
+		.route(
+			"/api/recipe/archive",
+			get(export_archive)
+				.post(import_archive)
+				.layer(DefaultBodyLimit::max(1024 * 1024 * 200)),
+		)
+		.route("/api/recipe/:id", get(get_recipe).put(edit_recipe))
+		.route("/api/recipe/:id/jsonld", get(get_recipe_jsonld))
 		.route("/api/recipe/list", get(list_recipes))
+		.route("/api/recipe/search", get(search_recipes))
+		.route("/api/recipe/tags", get(list_tags))
+		.route("/api/recipe/:id/revisions", get(list_revisions))
+		.route("/api/recipe/:id/revisions/:num", get(get_revision))
+		.route(
+			"/api/recipe/:id/revisions/:num/diff/:other",
+			get(diff_revisions),
+		)
+		.route(
+			"/api/recipe/:id/revisions/:num/rollback",
+			post(rollback_revision),
+		)
 		.with_state(state)
 }
 
@@ -33,11 +66,7 @@ struct CreateRecipeResponse {
 	id: Uuid,
 }
 
-async fn create_recipe(
-	State(state): State<Arc<AppState>>,
-	user: User,
-	Json(recipe): Json<RecipeCreation>,
-) -> AppResult<Json<CreateRecipeResponse>> {
+fn validate_recipe(recipe: &RecipeCreation) -> AppResult<()> {
 	if recipe.name.is_empty() {
 		return Err(AppError::bad_request("Title cannot be empty"));
 	}
@@ -54,6 +83,12 @@ async fn create_recipe(
 			return Err(AppError::bad_request("Steps cannot be empty"));
 		}
 	}
+	if recipe.servings.is_some_and(|s| !(1..=1000).contains(&s)) {
+		return Err(AppError::bad_request("Servings must be between 1 and 1000"));
+	}
+	for tag in &recipe.tags {
+		Tag::validate(tag)?;
+	}
 	for ingredient in &recipe.ingredients {
 		if ingredient.name.is_empty() {
 			return Err(AppError::bad_request("Ingredient name cannot be empty"));
@@ -67,6 +102,15 @@ async fn create_recipe(
 			));
 		}
 	}
+	Ok(())
+}
+
+async fn create_recipe(
+	State(state): State<Arc<AppState>>,
+	VerifiedUser { user }: VerifiedUser,
+	Json(recipe): Json<RecipeCreation>,
+) -> AppResult<Json<CreateRecipeResponse>> {
+	validate_recipe(&recipe)?;
 	let created_recipe = Recipe::create(&state.pool, &user.id, &recipe).await?;
 	info!(
 		"User {} created recipe {}",
@@ -77,33 +121,174 @@ async fn create_recipe(
 	}))
 }
 
+async fn import_recipe(
+	user: User,
+	headers: HeaderMap,
+	body: Bytes,
+) -> AppResult<Json<RecipeImport>> {
+	let content_type = headers
+		.get(header::CONTENT_TYPE)
+		.and_then(|c| c.to_str().ok())
+		.unwrap_or_default();
+	if !["text/html", "application/json", "application/ld+json"]
+		.iter()
+		.any(|t| content_type.starts_with(t))
+	{
+		return Err(AppError::bad_request(
+			"Expected an HTML or JSON-LD document",
+		));
+	}
+	let document =
+		std::str::from_utf8(&body).map_err(|_| AppError::bad_request("Document is not UTF-8"))?;
+	let imported = schema_org::import(document)?;
+	info!(
+		"User {} imported recipe \"{}\"",
+		user.id, imported.recipe.name
+	);
+	Ok(Json(imported))
+}
+
+async fn export_archive(
+	State(state): State<Arc<AppState>>,
+	user: User,
+	Query(params): Query<HashMap<String, String>>,
+) -> AppResult<impl IntoResponse> {
+	let all = params.get("all").is_some_and(|a| a == "true");
+	if all && !user.is_admin {
+		return Err(AppError::forbidden("Only admins can export all recipes"));
+	}
+	let author = (!all).then_some(&user.id);
+	let ids = Recipe::list_ids(&state.pool, author).await?;
+	info!("User {} exported {} recipes", user.id, ids.len());
+	let export = ArchiveExport::new(state.pool.clone(), state.images.clone(), ids);
+	Ok((
+		[
+			(header::CONTENT_TYPE, "application/x-tar"),
+			(
+				header::CONTENT_DISPOSITION,
+				"attachment; filename=\"recipes.tar\"",
+			),
+		],
+		StreamBody::new(export.into_stream()),
+	))
+}
+
+async fn import_archive(
+	State(state): State<Arc<AppState>>,
+	VerifiedUser { user }: VerifiedUser,
+	body: Bytes,
+) -> AppResult<Json<ArchiveImport>> {
+	let contents = archive::read_archive(&body)?;
+	// Check everything up front, so a bad recipe fails the import before any image is uploaded
+	for (_, recipe) in &contents.recipes {
+		validate_recipe(recipe)?;
+	}
+	let imported = archive::import(&state.pool, &*state.images, contents, &user.id).await?;
+	info!(
+		"User {} imported {} recipes and {} images",
+		user.id,
+		imported.recipes.len(),
+		imported.images.len()
+	);
+	Ok(Json(imported))
+}
+
+async fn edit_recipe(
+	State(state): State<Arc<AppState>>,
+	VerifiedUser { user }: VerifiedUser,
+	Path(id): Path<Uuid>,
+	Json(recipe): Json<RecipeCreation>,
+) -> AppResult<Json<Recipe>> {
+	validate_recipe(&recipe)?;
+	let updated_recipe = Recipe::update(&state.pool, &id, &user, &recipe).await?;
+	info!(
+		"User {} edited recipe {}",
+		user.id, updated_recipe.metadata.id
+	);
+	Ok(Json(updated_recipe))
+}
+
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct GetRecipeResponse {
 	recipe: Recipe,
 	author: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	original_servings: Option<i32>,
 }
 
 async fn get_recipe(
 	State(state): State<Arc<AppState>>,
 	Path(id): Path<Uuid>,
+	Query(params): Query<HashMap<String, String>>,
 ) -> AppResult<Json<GetRecipeResponse>> {
-	let recipe = Recipe::from_uuid(&state.pool, &id).await?;
+	let mut recipe = Recipe::from_uuid(&state.pool, &id).await?;
 	let author = User::from_uuid(&state.pool, &recipe.metadata.author).await?;
+	let mut original_servings = None;
+	if let Some(servings) = params.get("servings") {
+		let servings = servings
+			.parse::<i32>()
+			.ok()
+			.filter(|s| (1..=1000).contains(s))
+			.ok_or(AppError::bad_request("Servings must be between 1 and 1000"))?;
+		original_servings = recipe.metadata.servings;
+		recipe.scale_to(servings)?;
+	}
+	if let Some(units) = params.get("units") {
+		let system = match units.as_str() {
+			"metric" => UnitSystem::Metric,
+			"imperial" => UnitSystem::Imperial,
+			_ => return Err(AppError::bad_request("Units must be metric or imperial")),
+		};
+		recipe.convert_units(system);
+	}
 	Ok(Json(GetRecipeResponse {
 		author: author.username,
 		recipe,
+		original_servings,
 	}))
 }
 
+async fn get_recipe_jsonld(
+	State(state): State<Arc<AppState>>,
+	Path(id): Path<Uuid>,
+) -> AppResult<impl IntoResponse> {
+	let recipe = Recipe::from_uuid(&state.pool, &id).await?;
+	let author = User::from_uuid(&state.pool, &recipe.metadata.author).await?;
+	let document = schema_org::export(&recipe, &author.username, &state.secrets.frontend_url);
+	Ok((
+		[(header::CONTENT_TYPE, "application/ld+json")],
+		document.to_string(),
+	))
+}
+
 async fn list_recipes(
 	State(state): State<Arc<AppState>>,
 	Query(params): Query<HashMap<String, String>>,
-) -> AppResult<Json<Vec<RecipeMetadata>>> {
-	let limit = params
+) -> AppResult<Json<RecipePage>> {
+	Ok(Json(list_page(&state, &params, None).await?))
+}
+
+/// Most results a single request can ask for
+const MAX_PAGE_SIZE: u64 = 100;
+
+/// The `limit` query parameter, kept within bounds
+fn page_size(params: &HashMap<String, String>) -> u64 {
+	params
 		.get("limit")
 		.map(|s| s.parse::<u64>().unwrap_or(10))
-		.unwrap_or(10);
+		.unwrap_or(10)
+		.clamp(1, MAX_PAGE_SIZE)
+}
+
+/// Lists a page of recipes, optionally only those by `author`, as requested by the
+/// `limit`, `order`, `cursor`, `tags`, `tagMode` and `count` query parameters
+pub async fn list_page(
+	state: &AppState,
+	params: &HashMap<String, String>,
+	author: Option<Uuid>,
+) -> AppResult<RecipePage> {
+	let limit = page_size(params);
 	let sort_order = params
 		.get("order")
 		.map(|s| match s.as_str() {
@@ -114,6 +299,115 @@ async fn list_recipes(
 			_ => RecipeListSort::DateAscending,
 		})
 		.unwrap_or(RecipeListSort::DateAscending);
-	let recipes = Recipe::list_brief(&state.pool, limit, sort_order).await?;
-	Ok(Json(recipes))
+	let cursor = params
+		.get("cursor")
+		.map(|c| RecipeCursor::decode(c))
+		.transpose()?;
+	let filter = RecipeListFilter {
+		tags: params
+			.get("tags")
+			.map(|t| {
+				t.split(',')
+					.map(Tag::normalize)
+					.filter(|t| !t.is_empty())
+					.collect()
+			})
+			.unwrap_or_default(),
+		match_all_tags: params.get("tagMode").map(String::as_str) != Some("any"),
+		author,
+	};
+	let mut page =
+		Recipe::list_brief(&state.pool, limit, sort_order, cursor.as_ref(), &filter).await?;
+	if params.get("count").is_some_and(|c| c == "true") {
+		page.total = Some(Recipe::count(&state.pool, &filter).await?);
+	}
+	Ok(page)
+}
+
+async fn search_recipes(
+	State(state): State<Arc<AppState>>,
+	Query(params): Query<HashMap<String, String>>,
+) -> AppResult<Json<Vec<RecipeSearchResult>>> {
+	let query = params
+		.get("q")
+		.map(|q| q.trim())
+		.filter(|q| !q.is_empty())
+		.ok_or(AppError::bad_request("Missing search query"))?;
+	let limit = page_size(&params);
+	let weight = |key: &str, default: f32| -> AppResult<f32> {
+		params.get(key).map_or(Ok(default), |s| {
+			s.parse::<f32>()
+				.ok()
+				.filter(|w| (0.0..=1.0).contains(w))
+				.ok_or(AppError::bad_request("Weights must be between 0 and 1"))
+		})
+	};
+	let defaults = RecipeSearchWeights::default();
+	let weights = RecipeSearchWeights {
+		title: weight("titleWeight", defaults.title)?,
+		ingredients: weight("ingredientWeight", defaults.ingredients)?,
+		description: weight("descriptionWeight", defaults.description)?,
+		steps: weight("stepWeight", defaults.steps)?,
+	};
+	let results = Recipe::search(&state.pool, query, limit, weights).await?;
+	Ok(Json(results))
+}
+
+async fn list_tags(State(state): State<Arc<AppState>>) -> AppResult<Json<Vec<Tag>>> {
+	let tags = Tag::get_all(&state.pool).await?;
+	Ok(Json(tags))
+}
+
+async fn list_revisions(
+	State(state): State<Arc<AppState>>,
+	Path(id): Path<Uuid>,
+) -> AppResult<Json<Vec<RecipeRevisionBrief>>> {
+	let recipe = Recipe::from_uuid(&state.pool, &id).await?;
+	let revisions = RecipeRevision::list(&state.pool, &recipe.metadata.id).await?;
+	Ok(Json(revisions))
+}
+
+async fn get_revision(
+	State(state): State<Arc<AppState>>,
+	Path((id, num)): Path<(Uuid, i32)>,
+) -> AppResult<Json<RecipeRevision>> {
+	let revision = RecipeRevision::from_num(&state.pool, &id, num).await?;
+	Ok(Json(revision))
+}
+
+async fn diff_revisions(
+	State(state): State<Arc<AppState>>,
+	Path((id, num, other)): Path<(Uuid, i32, i32)>,
+) -> AppResult<Json<RecipeDiff>> {
+	let from = RecipeRevision::from_num(&state.pool, &id, num).await?;
+	let to = RecipeRevision::from_num(&state.pool, &id, other).await?;
+	Ok(Json(from.diff(&to)))
+}
+
+async fn rollback_revision(
+	State(state): State<Arc<AppState>>,
+	VerifiedUser { user }: VerifiedUser,
+	Path((id, num)): Path<(Uuid, i32)>,
+) -> AppResult<Json<Recipe>> {
+	let revision = RecipeRevision::from_num(&state.pool, &id, num).await?;
+	let mut data = RecipeCreation::from(revision.recipe);
+	// Images may have been deleted since the revision was made
+	let image_ids: Vec<Uuid> = data
+		.image_id
+		.iter()
+		.chain(data.steps.iter().filter_map(|s| s.image_id.as_ref()))
+		.copied()
+		.collect();
+	let existing_images = Image::existing(&state.pool, &image_ids).await?;
+	let keep = |id: Option<Uuid>| id.filter(|id| existing_images.contains(id));
+	data.image_id = keep(data.image_id);
+	for step in &mut data.steps {
+		step.image_id = keep(step.image_id);
+	}
+	let restored = Recipe::update(&state.pool, &id, &user, &data).await?;
+	info!(
+		"User {} rolled back recipe {} to revision {}",
+		user.id, restored.metadata.id, num
+	);
+	Ok(Json(restored))
 }
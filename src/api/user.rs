@@ -1,16 +1,35 @@
-use std::sync::Arc;
+use std::{
+	collections::HashMap,
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+};
 
 use axum::{
-	extract::State,
-	routing::{get, post},
+	extract::{ConnectInfo, Path, Query, State},
+	http::{header, HeaderMap},
+	routing::{delete, get, post},
 	Json, Router,
 };
-use serde::{Deserialize, Serialize};
-use tracing::info;
+use serde::{Deserialize, Deserializer, Serialize};
+use tracing::{info, warn};
+use uuid::Uuid;
 
 use crate::{
+	api::recipe::list_page,
 	error::{AppError, AppResult},
-	models::user::User,
+	external::mail::Mail,
+	models::{
+		api_token::{ApiToken, Scope},
+		email_verification::{EmailVerification, VERIFICATION_VALIDITY_HOURS},
+		image::Image,
+		login_failure::LoginFailures,
+		password_reset::{PasswordReset, RESET_VALIDITY_MINUTES},
+		profile::PublicProfile,
+		recipe::RecipePage,
+		session::Session,
+		two_factor::{LoginChallenge, TwoFactor},
+		user::{bearer_token, validate_username, User},
+	},
 	AppState,
 };
 
@@ -18,7 +37,30 @@ pub fn user_router(state: Arc<AppState>) -> Router {
 	Router::new()
 		.route("/api/user/create", post(create_user))
 		.route("/api/user/login", post(login_user))
-		.route("/api/user/self", get(get_self))
+		.route("/api/user/login/2fa", post(login_two_factor))
+		.route("/api/user/2fa/enroll", post(enroll_two_factor))
+		.route("/api/user/2fa/confirm", post(confirm_two_factor))
+		.route("/api/user/2fa/disable", post(disable_two_factor))
+		.route(
+			"/api/user/2fa/recovery-codes",
+			post(regenerate_recovery_codes),
+		)
+		.route(
+			"/api/user/self",
+			get(get_self).put(update_self).delete(delete_self),
+		)
+		.route("/api/user/logout", post(logout_user))
+		.route("/api/user/sessions", get(list_sessions))
+		.route("/api/user/sessions/:id", delete(revoke_session))
+		.route("/api/user/tokens", get(list_tokens).post(create_token))
+		.route("/api/user/tokens/:id", delete(delete_token))
+		.route("/api/user/verify", post(verify_email))
+		.route("/api/user/verify/resend", post(resend_verification))
+		.route("/api/user/password/forgot", post(forgot_password))
+		.route("/api/user/password/reset", post(reset_password))
+		.route("/api/user/password/change", post(change_password))
+		.route("/api/user/:username", get(get_profile))
+		.route("/api/user/:username/recipes", get(list_profile_recipes))
 		.with_state(state)
 }
 
@@ -71,21 +113,72 @@ async fn create_user(
 			}
 		}
 	}
-	if username.len() < 3 {
-		return Err(AppError::bad_request(
-			"Username must be at least 3 characters",
-		));
+	validate_username(&username)?;
+	validate_password(&password)?;
+	validate_email(&email)?;
+	let user = User::create(&state.pool, &username, &email, &password, &false).await?;
+	// The account exists either way, a failed mail can be resent later
+	if let Err(e) = send_verification_mail(&state, &user).await {
+		warn!(
+			"Failed to send verification mail to user {}: {}",
+			user.id, e
+		);
+	}
+	Ok("User created")
+}
+
+async fn send_verification_mail(state: &AppState, user: &User) -> AppResult<()> {
+	let token = EmailVerification::create(&state.pool, user).await?;
+	let mail = Mail {
+		to: user.email.clone(),
+		subject: "Verify your Cromptch email address".to_string(),
+		body: format!(
+			"Hi {},\n\nWelcome to Cromptch! Please open this link within {} hours \
+			to verify your email address:\n\n{}/verify?token={}",
+			user.username, VERIFICATION_VALIDITY_HOURS, state.secrets.frontend_url, token
+		),
+	};
+	state.mailer.send(&mail).await?;
+	info!("Sent verification mail to user {}", user.id);
+	Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailRequest {
+	pub token: String,
+}
+
+async fn verify_email(
+	State(state): State<Arc<AppState>>,
+	Json(VerifyEmailRequest { token }): Json<VerifyEmailRequest>,
+) -> AppResult<&'static str> {
+	let user = EmailVerification::redeem(&state.pool, &token).await?;
+	info!("User {} verified their email address", user.id);
+	Ok("Email address verified")
+}
+
+async fn resend_verification(
+	State(state): State<Arc<AppState>>,
+	user: User,
+) -> AppResult<&'static str> {
+	send_verification_mail(&state, &user).await?;
+	Ok("Verification mail sent")
+}
+
+fn validate_email(email: &str) -> AppResult<()> {
+	if !email.contains('@') {
+		return Err(AppError::bad_request("Invalid email address"));
 	}
+	Ok(())
+}
+
+fn validate_password(password: &str) -> AppResult<()> {
 	if password.len() < 8 {
 		return Err(AppError::bad_request(
 			"Password must be at least 8 characters",
 		));
 	}
-	if !email.contains('@') {
-		return Err(AppError::bad_request("Invalid email address"));
-	}
-	User::create(&state.pool, &username, &email, &password, &false).await?;
-	Ok("User created")
+	Ok(())
 }
 
 #[derive(Deserialize)]
@@ -104,23 +197,360 @@ pub struct LoginUserResponse {
 	pub token: String,
 }
 
-async fn login_user(
-	State(state): State<Arc<AppState>>,
-	Json(LoginUserRequest { email, password }): Json<LoginUserRequest>,
-) -> AppResult<Json<LoginUserResponse>> {
-	info!("Logging in user {}...", email);
-	let user = User::from_login(&state.pool, &email, &password).await?;
-	let token = user.create_token(&state.pool).await?;
-	info!("User {} logged in", email);
-	Ok(Json(LoginUserResponse {
+/// The address of the client. Forwarding headers are only believed when they come from a
+/// trusted proxy, as anyone else can put whatever they like in them.
+fn client_address(state: &AppState, headers: &HeaderMap, peer: &SocketAddr) -> String {
+	let trusted = &state.secrets.trusted_proxies;
+	let mut address = peer.ip();
+	if !trusted.contains(&address) {
+		return address.to_string();
+	}
+	let forwarded: Vec<&str> = headers
+		.get_all("X-Forwarded-For")
+		.iter()
+		.filter_map(|h| h.to_str().ok())
+		.flat_map(|h| h.split(','))
+		.map(str::trim)
+		.collect();
+	if forwarded.is_empty() {
+		return headers
+			.get("X-Real-IP")
+			.and_then(|h| h.to_str().ok())
+			.and_then(|h| h.trim().parse::<IpAddr>().ok())
+			.unwrap_or(address)
+			.to_string();
+	}
+	// Every proxy appends the address it saw, so the client is the last one added by a trusted proxy.
+	// Anything before that came from the client.
+	for hop in forwarded.iter().rev() {
+		if !trusted.contains(&address) {
+			break;
+		}
+		match hop.parse() {
+			Ok(hop) => address = hop,
+			Err(_) => break,
+		}
+	}
+	address.to_string()
+}
+
+/// Logs in a user who has passed every check
+async fn start_session(
+	state: &AppState,
+	user: User,
+	headers: &HeaderMap,
+	peer: &SocketAddr,
+) -> AppResult<LoginUserResponse> {
+	// Only now, so a correct password alone does not reset the lockout on guessing the second factor
+	LoginFailures::clear(&state.pool, &user.email).await?;
+	let user_agent = headers
+		.get(header::USER_AGENT)
+		.and_then(|h| h.to_str().ok());
+	let token = user
+		.create_token(
+			&state.pool,
+			user_agent,
+			Some(&client_address(state, headers, peer)),
+		)
+		.await?;
+	info!("User {} logged in", user.email);
+	Ok(LoginUserResponse {
 		id: user.id.to_string(),
 		username: user.username,
 		email: user.email,
 		is_admin: user.is_admin,
 		token,
+	})
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TwoFactorChallengeResponse {
+	pub two_factor_required: bool,
+	/// Passed to `/api/user/login/2fa` along with a code
+	pub challenge: String,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+	LoggedIn(LoginUserResponse),
+	TwoFactor(TwoFactorChallengeResponse),
+}
+
+/// Logs in a user whose password or external identity has been checked, asking for
+/// a second factor first if they have one
+pub async fn complete_login(
+	state: &AppState,
+	user: User,
+	headers: &HeaderMap,
+	peer: &SocketAddr,
+) -> AppResult<LoginResponse> {
+	if user.two_factor_enabled {
+		// Wrong codes count against the account, so it cannot get new challenges to keep guessing with
+		LoginFailures::check(
+			&state.pool,
+			&user.email,
+			&client_address(state, headers, peer),
+		)
+		.await?;
+		let challenge = LoginChallenge::create(&state.pool, &user).await?;
+		info!("User {} needs to enter a second factor", user.email);
+		return Ok(LoginResponse::TwoFactor(TwoFactorChallengeResponse {
+			two_factor_required: true,
+			challenge,
+		}));
+	}
+	let response = start_session(state, user, headers, peer).await?;
+	Ok(LoginResponse::LoggedIn(response))
+}
+
+async fn login_user(
+	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	Json(LoginUserRequest { email, password }): Json<LoginUserRequest>,
+) -> AppResult<Json<LoginResponse>> {
+	info!("Logging in user {}...", email);
+	let address = client_address(&state, &headers, &peer);
+	LoginFailures::check(&state.pool, &email, &address).await?;
+	let user = match User::from_login(&state.pool, &email, &password).await {
+		Ok(user) => user,
+		Err(e) => {
+			LoginFailures::record(&state.pool, &email, &address).await?;
+			warn!("Failed login for {} from {}", email, address);
+			return Err(e);
+		}
+	};
+	let response = complete_login(&state, user, &headers, &peer).await?;
+	Ok(Json(response))
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorLoginRequest {
+	pub challenge: String,
+	/// A code from an authenticator app, or a recovery code
+	pub code: String,
+}
+
+async fn login_two_factor(
+	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	Json(TwoFactorLoginRequest { challenge, code }): Json<TwoFactorLoginRequest>,
+) -> AppResult<Json<LoginUserResponse>> {
+	let user = LoginChallenge::attempt(&state.pool, &challenge).await?;
+	let address = client_address(&state, &headers, &peer);
+	LoginFailures::check(&state.pool, &user.email, &address).await?;
+	if let Err(e) = LoginChallenge::complete(&state.pool, &challenge, &user, &code).await {
+		LoginFailures::record(&state.pool, &user.email, &address).await?;
+		warn!("Failed second factor for {} from {}", user.email, address);
+		return Err(e);
+	}
+	let response = start_session(&state, user, &headers, &peer).await?;
+	Ok(Json(response))
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EnrollTwoFactorResponse {
+	pub secret: String,
+	pub otpauth_uri: String,
+}
+
+async fn enroll_two_factor(
+	State(state): State<Arc<AppState>>,
+	user: User,
+) -> AppResult<Json<EnrollTwoFactorResponse>> {
+	let (secret, otpauth_uri) = TwoFactor::enroll(&state.pool, &user).await?;
+	Ok(Json(EnrollTwoFactorResponse {
+		secret,
+		otpauth_uri,
 	}))
 }
 
+#[derive(Deserialize)]
+pub struct TwoFactorCodeRequest {
+	pub code: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecoveryCodesResponse {
+	pub recovery_codes: Vec<String>,
+}
+
+async fn confirm_two_factor(
+	State(state): State<Arc<AppState>>,
+	user: User,
+	Json(TwoFactorCodeRequest { code }): Json<TwoFactorCodeRequest>,
+) -> AppResult<Json<RecoveryCodesResponse>> {
+	let recovery_codes = TwoFactor::confirm(&state.pool, &user, &code).await?;
+	info!("User {} enabled two-factor authentication", user.id);
+	Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+/// Checks a second factor before sensitive changes to an account. Wrong codes count towards
+/// the login lockout, so a stolen session cannot be used to guess them.
+async fn require_code(state: &AppState, user: &User, code: &str, address: &str) -> AppResult<()> {
+	if !user.two_factor_enabled {
+		return Err(AppError::bad_request(
+			"Two-factor authentication is not enabled",
+		));
+	}
+	LoginFailures::check(&state.pool, &user.email, address).await?;
+	if !TwoFactor::verify(&state.pool, &user.id, code).await? {
+		LoginFailures::record(&state.pool, &user.email, address).await?;
+		warn!("Failed second factor for {} from {}", user.email, address);
+		return Err(AppError::unauthorized("Invalid code"));
+	}
+	Ok(())
+}
+
+/// Checks the current password before sensitive changes to an account. Wrong passwords count
+/// towards the login lockout, so a stolen session cannot be used to guess them.
+async fn require_password(
+	state: &AppState,
+	user: &User,
+	password: &str,
+	address: &str,
+) -> AppResult<()> {
+	LoginFailures::check(&state.pool, &user.email, address).await?;
+	if !user.check_password(password) {
+		LoginFailures::record(&state.pool, &user.email, address).await?;
+		warn!("Failed password check for {} from {}", user.email, address);
+		return Err(AppError::unauthorized("Current password is incorrect"));
+	}
+	Ok(())
+}
+
+async fn disable_two_factor(
+	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	user: User,
+	Json(TwoFactorCodeRequest { code }): Json<TwoFactorCodeRequest>,
+) -> AppResult<&'static str> {
+	if user.is_admin && state.secrets.require_admin_two_factor {
+		return Err(AppError::forbidden(
+			"Admins must use two-factor authentication",
+		));
+	}
+	let address = client_address(&state, &headers, &peer);
+	require_code(&state, &user, &code, &address).await?;
+	TwoFactor::disable(&state.pool, &user.id).await?;
+	info!("User {} disabled two-factor authentication", user.id);
+	Ok("Two-factor authentication disabled")
+}
+
+async fn regenerate_recovery_codes(
+	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	user: User,
+	Json(TwoFactorCodeRequest { code }): Json<TwoFactorCodeRequest>,
+) -> AppResult<Json<RecoveryCodesResponse>> {
+	let address = client_address(&state, &headers, &peer);
+	require_code(&state, &user, &code, &address).await?;
+	let recovery_codes = TwoFactor::regenerate_recovery_codes(&state.pool, &user.id).await?;
+	info!("User {} regenerated their recovery codes", user.id);
+	Ok(Json(RecoveryCodesResponse { recovery_codes }))
+}
+
+async fn logout_user(
+	State(state): State<Arc<AppState>>,
+	user: User,
+	headers: HeaderMap,
+) -> AppResult<&'static str> {
+	Session::revoke_token(&state.pool, bearer_token(&headers)?).await?;
+	info!("User {} logged out", user.id);
+	Ok("Logged out")
+}
+
+async fn list_sessions(
+	State(state): State<Arc<AppState>>,
+	user: User,
+	headers: HeaderMap,
+) -> AppResult<Json<Vec<Session>>> {
+	let sessions = Session::list(&state.pool, &user.id, bearer_token(&headers)?).await?;
+	Ok(Json(sessions))
+}
+
+async fn revoke_session(
+	State(state): State<Arc<AppState>>,
+	user: User,
+	Path(id): Path<Uuid>,
+) -> AppResult<&'static str> {
+	Session::revoke(&state.pool, &user.id, &id).await?;
+	info!("User {} revoked session {}", user.id, id);
+	Ok("Session revoked")
+}
+
+async fn list_tokens(
+	State(state): State<Arc<AppState>>,
+	user: User,
+) -> AppResult<Json<Vec<ApiToken>>> {
+	let tokens = ApiToken::list(&state.pool, &user.id).await?;
+	Ok(Json(tokens))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateTokenRequest {
+	pub name: String,
+	pub scopes: Vec<Scope>,
+	pub expires_in_days: Option<i32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CreateTokenResponse {
+	#[serde(flatten)]
+	pub info: ApiToken,
+	/// Only ever shown here, it cannot be retrieved later
+	pub token: String,
+}
+
+async fn create_token(
+	State(state): State<Arc<AppState>>,
+	user: User,
+	Json(CreateTokenRequest {
+		name,
+		scopes,
+		expires_in_days,
+	}): Json<CreateTokenRequest>,
+) -> AppResult<Json<CreateTokenResponse>> {
+	let name = name.trim();
+	if name.is_empty() || name.len() > 64 {
+		return Err(AppError::bad_request(
+			"Token name must be between 1 and 64 characters",
+		));
+	}
+	if scopes.is_empty() {
+		return Err(AppError::bad_request("Token needs at least one scope"));
+	}
+	if scopes.contains(&Scope::Admin) && !user.is_admin {
+		return Err(AppError::forbidden("Only admins can create admin tokens"));
+	}
+	if expires_in_days.is_some_and(|d| d < 1) {
+		return Err(AppError::bad_request("Expiry must be at least one day"));
+	}
+	let (info, token) =
+		ApiToken::create(&state.pool, &user, name, &scopes, expires_in_days).await?;
+	info!("User {} created API token {}", user.id, info.id);
+	Ok(Json(CreateTokenResponse { info, token }))
+}
+
+async fn delete_token(
+	State(state): State<Arc<AppState>>,
+	user: User,
+	Path(id): Path<Uuid>,
+) -> AppResult<&'static str> {
+	ApiToken::delete(&state.pool, &user.id, &id).await?;
+	info!("User {} deleted API token {}", user.id, id);
+	Ok("Token deleted")
+}
+
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UserSelfResponse {
@@ -128,13 +558,296 @@ pub struct UserSelfResponse {
 	pub username: String,
 	pub email: String,
 	pub is_admin: bool,
+	pub is_verified: bool,
+	pub two_factor_enabled: bool,
+	/// Whether the user needs to enable 2FA to keep their privileges
+	pub two_factor_required: bool,
 }
 
-async fn get_self(user: User) -> AppResult<Json<UserSelfResponse>> {
-	Ok(Json(UserSelfResponse {
-		id: user.id.to_string(),
-		username: user.username,
-		email: user.email,
-		is_admin: user.is_admin,
-	}))
+impl UserSelfResponse {
+	async fn new(state: &AppState, user: User) -> AppResult<Self> {
+		// The extractor withholds admin rights until 2FA is enabled, so look at the stored role
+		let two_factor_required = !user.two_factor_enabled
+			&& state.secrets.require_admin_two_factor
+			&& User::from_uuid(&state.pool, &user.id)
+				.await?
+				.needs_two_factor_for_admin(state);
+		Ok(UserSelfResponse {
+			id: user.id.to_string(),
+			username: user.username,
+			email: user.email,
+			is_admin: user.is_admin && !two_factor_required,
+			is_verified: user.verified_at.is_some(),
+			two_factor_enabled: user.two_factor_enabled,
+			two_factor_required,
+		})
+	}
+}
+
+async fn get_self(
+	State(state): State<Arc<AppState>>,
+	user: User,
+) -> AppResult<Json<UserSelfResponse>> {
+	Ok(Json(UserSelfResponse::new(&state, user).await?))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateSelfRequest {
+	pub username: Option<String>,
+	pub email: Option<String>,
+	/// Needed to change the email address
+	pub current_password: Option<String>,
+	/// An empty string clears the display name
+	pub display_name: Option<String>,
+	/// An empty string clears the bio
+	pub bio: Option<String>,
+	/// `null` removes the avatar, leaving the field out keeps it
+	#[serde(default, deserialize_with = "present")]
+	pub avatar_id: Option<Option<Uuid>>,
+}
+
+/// Tells a field that is `null` apart from one that is missing
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+	D: Deserializer<'de>,
+	T: Deserialize<'de>,
+{
+	Option::deserialize(deserializer).map(Some)
+}
+
+async fn update_self(
+	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	user: User,
+	Json(UpdateSelfRequest {
+		username,
+		email,
+		current_password,
+		display_name,
+		bio,
+		avatar_id,
+	}): Json<UpdateSelfRequest>,
+) -> AppResult<Json<UserSelfResponse>> {
+	let username = username.filter(|u| *u != user.username);
+	let email = email.filter(|e| *e != user.email);
+	// Check everything up front, so a request either applies completely or not at all
+	if let Some(username) = &username {
+		validate_username(username)?;
+	}
+	if let Some(email) = &email {
+		validate_email(email)?;
+		// Whoever controls the email address can reset the password, so this needs more than a session
+		let address = client_address(&state, &headers, &peer);
+		require_password(
+			&state,
+			&user,
+			&current_password.unwrap_or_default(),
+			&address,
+		)
+		.await?;
+	}
+	let display_name = display_name.as_deref().map(str::trim);
+	if display_name.is_some_and(|d| d.chars().count() > 64) {
+		return Err(AppError::bad_request(
+			"Display name cannot be longer than 64 characters",
+		));
+	}
+	let bio = bio.as_deref().map(str::trim);
+	if bio.is_some_and(|b| b.chars().count() > 1000) {
+		return Err(AppError::bad_request(
+			"Bio cannot be longer than 1000 characters",
+		));
+	}
+	if let Some(Some(avatar_id)) = &avatar_id {
+		if Image::from_uuid(&state.pool, avatar_id).await?.owner != Some(user.id) {
+			return Err(AppError::forbidden("You can only use your own images"));
+		}
+	}
+
+	let mut tx = state
+		.pool
+		.begin()
+		.await
+		.map_err(|_| AppError::internal("Failed to start transaction"))?;
+	if let Some(username) = &username {
+		user.set_username(&mut tx, username).await?;
+	}
+	if let Some(email) = &email {
+		user.set_email(&mut tx, email).await?;
+	}
+	PublicProfile::update(&mut tx, &user.id, display_name, bio, avatar_id).await?;
+	tx.commit()
+		.await
+		.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+	if let Some(username) = &username {
+		info!("User {} changed their username to {}", user.id, username);
+	}
+	if email.is_some() {
+		info!("User {} changed their email address", user.id);
+	}
+	let user = User::from_uuid(&state.pool, &user.id).await?;
+	if email.is_some() {
+		if let Err(e) = send_verification_mail(&state, &user).await {
+			warn!(
+				"Failed to send verification mail to user {}: {}",
+				user.id, e
+			);
+		}
+	}
+	Ok(Json(UserSelfResponse::new(&state, user).await?))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RecipeHandling {
+	/// Delete the recipes along with the account
+	Delete,
+	/// Keep the recipes, attributed to a placeholder account
+	Transfer,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteSelfRequest {
+	pub password: String,
+	/// Needed if the user has two-factor authentication enabled
+	pub code: Option<String>,
+	pub recipes: RecipeHandling,
+}
+
+async fn delete_self(
+	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	user: User,
+	Json(DeleteSelfRequest {
+		password,
+		code,
+		recipes,
+	}): Json<DeleteSelfRequest>,
+) -> AppResult<&'static str> {
+	let address = client_address(&state, &headers, &peer);
+	require_password(&state, &user, &password, &address).await?;
+	if user.two_factor_enabled {
+		require_code(&state, &user, &code.unwrap_or_default(), &address).await?;
+	}
+	let transfer = matches!(recipes, RecipeHandling::Transfer);
+	user.delete(&state.pool, &*state.images, transfer).await?;
+	info!(
+		"User {} deleted their account, {} their recipes",
+		user.id,
+		match transfer {
+			true => "transferring",
+			false => "deleting",
+		}
+	);
+	Ok("Account deleted")
+}
+
+#[derive(Deserialize)]
+pub struct ForgotPasswordRequest {
+	pub email: String,
+}
+
+async fn forgot_password(
+	State(state): State<Arc<AppState>>,
+	Json(ForgotPasswordRequest { email }): Json<ForgotPasswordRequest>,
+) -> AppResult<&'static str> {
+	// Everything happens in the background, so neither the response nor its timing tells
+	// whether the account exists
+	tokio::spawn(async move {
+		if let Err(e) = send_reset_mail(&state, &email).await {
+			warn!("Failed to send password reset mail for {}: {}", email, e);
+		}
+	});
+	Ok("If an account with this email exists, a reset link has been sent")
+}
+
+async fn send_reset_mail(state: &AppState, email: &str) -> AppResult<()> {
+	let Ok(user) = User::from_email(&state.pool, email).await else {
+		info!("Password reset requested for unknown email {}", email);
+		return Ok(());
+	};
+	let token = PasswordReset::create(&state.pool, &user).await?;
+	let mail = Mail {
+		to: user.email.clone(),
+		subject: "Reset your Cromptch password".to_string(),
+		body: format!(
+			"Hi {},\n\nSomeone asked to reset the password of your Cromptch account. \
+			If that was you, open this link within {} minutes to choose a new password:\n\n\
+			{}/reset-password?token={}\n\nIf it wasn't you, you can ignore this mail.",
+			user.username, RESET_VALIDITY_MINUTES, state.secrets.frontend_url, token
+		),
+	};
+	state.mailer.send(&mail).await?;
+	info!("Sent password reset mail to user {}", user.id);
+	Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordRequest {
+	pub token: String,
+	pub password: String,
+}
+
+async fn reset_password(
+	State(state): State<Arc<AppState>>,
+	Json(ResetPasswordRequest { token, password }): Json<ResetPasswordRequest>,
+) -> AppResult<&'static str> {
+	validate_password(&password)?;
+	let user = PasswordReset::redeem(&state.pool, &token, &password).await?;
+	info!("User {} reset their password", user.id);
+	Ok("Password reset")
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePasswordRequest {
+	pub current_password: String,
+	pub new_password: String,
+}
+
+async fn change_password(
+	State(state): State<Arc<AppState>>,
+	ConnectInfo(peer): ConnectInfo<SocketAddr>,
+	headers: HeaderMap,
+	user: User,
+	Json(ChangePasswordRequest {
+		current_password,
+		new_password,
+	}): Json<ChangePasswordRequest>,
+) -> AppResult<&'static str> {
+	let address = client_address(&state, &headers, &peer);
+	require_password(&state, &user, &current_password, &address).await?;
+	validate_password(&new_password)?;
+	let mut tx = state
+		.pool
+		.begin()
+		.await
+		.map_err(|_| AppError::internal("Failed to start transaction"))?;
+	user.set_password(&mut tx, &new_password).await?;
+	tx.commit()
+		.await
+		.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+	info!("User {} changed their password", user.id);
+	Ok("Password changed, please log in again")
+}
+
+async fn get_profile(
+	State(state): State<Arc<AppState>>,
+	Path(username): Path<String>,
+) -> AppResult<Json<PublicProfile>> {
+	Ok(Json(
+		PublicProfile::from_username(&state.pool, &username).await?,
+	))
+}
+
+async fn list_profile_recipes(
+	State(state): State<Arc<AppState>>,
+	Path(username): Path<String>,
+	Query(params): Query<HashMap<String, String>>,
+) -> AppResult<Json<RecipePage>> {
+	let profile = PublicProfile::from_username(&state.pool, &username).await?;
+	Ok(Json(list_page(&state, &params, Some(profile.id)).await?))
 }
@@ -20,6 +20,7 @@ enum AppErrorKind {
 	Unauthorized,
 	Forbidden,
 	NotFound,
+	TooManyRequests,
 	InternalServerError,
 }
 
@@ -30,6 +31,7 @@ impl fmt::Display for AppError {
 			AppErrorKind::Unauthorized => write!(f, "Unauthorized: {}", self.message),
 			AppErrorKind::Forbidden => write!(f, "Forbidden: {}", self.message),
 			AppErrorKind::NotFound => write!(f, "Not Found: {}", self.message),
+			AppErrorKind::TooManyRequests => write!(f, "Too Many Requests: {}", self.message),
 			AppErrorKind::InternalServerError => {
 				write!(f, "Internal Server Error: {}", self.message)
 			}
@@ -37,6 +39,8 @@ impl fmt::Display for AppError {
 	}
 }
 
+impl std::error::Error for AppError {}
+
 impl From<AppErrorKind> for StatusCode {
 	fn from(val: AppErrorKind) -> Self {
 		match val {
@@ -44,6 +48,7 @@ impl From<AppErrorKind> for StatusCode {
 			AppErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
 			AppErrorKind::Forbidden => StatusCode::FORBIDDEN,
 			AppErrorKind::NotFound => StatusCode::NOT_FOUND,
+			AppErrorKind::TooManyRequests => StatusCode::TOO_MANY_REQUESTS,
 			AppErrorKind::InternalServerError => StatusCode::INTERNAL_SERVER_ERROR,
 		}
 	}
@@ -85,6 +90,13 @@ impl AppError {
 		}
 	}
 
+	pub fn too_many_requests(e: impl ToString) -> Self {
+		Self {
+			kind: AppErrorKind::TooManyRequests,
+			message: e.to_string(),
+		}
+	}
+
 	pub fn internal(e: impl ToString) -> Self {
 		Self {
 			kind: AppErrorKind::InternalServerError,
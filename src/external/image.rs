@@ -1,36 +1,118 @@
+use std::{io::Cursor, path::PathBuf, pin::Pin};
+
+use axum::async_trait;
 use bytes::Bytes;
+use futures_util::{Stream, TryStreamExt};
+use image::{imageops::FilterType, DynamicImage, ImageFormat, ImageReader, Limits};
+use reqwest::{header, StatusCode};
+use tokio_util::io::ReaderStream;
 use tracing::{info, warn};
 use uuid::Uuid;
 
 use crate::error::{AppError, AppResult};
 
-pub async fn get_image_bytes(pictrs_url: &str, id: &Uuid) -> AppResult<axum::body::Bytes> {
-	let full_picture_url = format!("{}/image/original/{}.webp", pictrs_url, id);
-	info!("Fetching image from {}", full_picture_url);
-	let response = reqwest::get(&full_picture_url)
-		.await
-		.map_err(|_| AppError::not_found("Image not found"))?;
-	let bytes = response
-		.bytes()
-		.await
-		.map_err(|_| AppError::internal("Error fetching image"))?;
-	Ok(bytes)
+/// Sizes thumbnails can be requested in, in pixels along the longest side. Only these are
+/// offered, so clients cannot have images processed at arbitrary sizes.
+pub const THUMBNAIL_SIZES: [u32; 4] = [128, 256, 512, 1024];
+
+/// One of the allowed thumbnail shapes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThumbnailVariant {
+	size: u32,
+	/// Cropped to a centered square rather than keeping the aspect ratio
+	square: bool,
 }
 
-pub async fn get_thumbnail_bytes(pictrs_url: &str, id: &Uuid) -> AppResult<axum::body::Bytes> {
-	let thumbnail_url = format!(
-		"{}/image/process.webp?thumbnail=512&src={}.webp",
-		pictrs_url, id
-	);
-	info!("Fetching image from {}", thumbnail_url);
-	let response = reqwest::get(&thumbnail_url)
-		.await
-		.map_err(|_| AppError::not_found("Image not found"))?;
-	let bytes = response
-		.bytes()
-		.await
-		.map_err(|_| AppError::internal("Error fetching image"))?;
-	Ok(bytes)
+impl Default for ThumbnailVariant {
+	fn default() -> Self {
+		Self {
+			size: 512,
+			square: false,
+		}
+	}
+}
+
+impl ThumbnailVariant {
+	pub fn new(size: u32, square: bool) -> AppResult<Self> {
+		if !THUMBNAIL_SIZES.contains(&size) {
+			return Err(AppError::bad_request(format!(
+				"Thumbnail size must be one of {}",
+				THUMBNAIL_SIZES.map(|s| s.to_string()).join(", ")
+			)));
+		}
+		Ok(Self { size, square })
+	}
+
+	pub fn all() -> impl Iterator<Item = Self> {
+		[false, true]
+			.into_iter()
+			.flat_map(|square| THUMBNAIL_SIZES.map(|size| Self { size, square }))
+	}
+
+	pub fn size(&self) -> u32 {
+		self.size
+	}
+
+	pub fn is_square(&self) -> bool {
+		self.square
+	}
+
+	/// Names the variant, like `256` or `256-square`
+	pub fn name(&self) -> String {
+		match self.square {
+			true => format!("{}-square", self.size),
+			false => self.size.to_string(),
+		}
+	}
+}
+
+/// An image that has been put into storage
+#[derive(Debug, Clone)]
+pub struct StoredImage {
+	pub id: Uuid,
+	/// Needed to delete the image again
+	pub delete_token: Uuid,
+	pub width: u32,
+	pub height: u32,
+}
+
+pub type ImageStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send>>;
+
+/// An image on its way out of storage
+pub struct ImageData {
+	pub content_type: String,
+	pub content_length: Option<u64>,
+	pub body: ImageStream,
+}
+
+impl ImageData {
+	fn from_bytes(content_type: &str, bytes: Bytes) -> Self {
+		ImageData {
+			content_type: content_type.to_string(),
+			content_length: Some(bytes.len() as u64),
+			body: Box::pin(futures_util::stream::once(async { Ok(bytes) })),
+		}
+	}
+
+	/// Reads the whole image into memory
+	pub async fn into_bytes(self) -> AppResult<Bytes> {
+		let chunks: Vec<Bytes> = self
+			.body
+			.try_collect()
+			.await
+			.map_err(|_| AppError::internal("Error fetching image"))?;
+		Ok(chunks.concat().into())
+	}
+}
+
+/// Where uploaded images are kept. Every image is stored as WebP.
+#[async_trait]
+pub trait ImageStorage: Send + Sync {
+	async fn upload(&self, image_bytes: &Bytes, file_name: &str) -> AppResult<StoredImage>;
+	async fn original(&self, id: &Uuid) -> AppResult<ImageData>;
+	async fn thumbnail(&self, id: &Uuid, variant: ThumbnailVariant) -> AppResult<ImageData>;
+	/// Images that are already gone count as deleted
+	async fn delete(&self, id: &Uuid, delete_token: &Uuid) -> AppResult<()>;
 }
 
 #[derive(serde::Deserialize)]
@@ -47,7 +129,6 @@ pub struct ImageUploadDetails {
 #[derive(serde::Deserialize)]
 pub struct ImageUploadFile {
 	pub delete_token: String,
-	#[allow(dead_code)]
 	pub details: ImageUploadDetails,
 	pub file: String,
 }
@@ -58,40 +139,423 @@ pub struct ImageUploadResponse {
 	pub msg: String,
 }
 
-pub async fn upload_image(
-	pictrs_url: &str,
-	image_bytes: &Bytes,
-	filename: &str,
-) -> AppResult<ImageUploadResponse> {
-	let file_part =
-		reqwest::multipart::Part::bytes(image_bytes.to_vec()).file_name(filename.to_string());
-	let form = reqwest::multipart::Form::new().part("images[]", file_part);
-	info!("Uploading image {}", filename);
-	let response = reqwest::Client::new()
-		.post(format!("{}/image", pictrs_url))
-		.multipart(form)
-		.send()
+/// Keeps images in a pict-rs instance, which is expected to convert uploads to WebP
+pub struct PictrsStorage {
+	url: String,
+}
+
+impl PictrsStorage {
+	pub fn new(url: &str) -> Self {
+		Self {
+			url: url.trim_end_matches('/').to_string(),
+		}
+	}
+
+	async fn fetch(&self, url: &str) -> AppResult<ImageData> {
+		info!("Fetching image from {}", url);
+		let response = reqwest::get(url)
+			.await
+			.map_err(|_| AppError::internal("Error fetching image"))?;
+		match response.status() {
+			status if status.is_success() => {}
+			StatusCode::NOT_FOUND => return Err(AppError::not_found("Image not found")),
+			StatusCode::BAD_REQUEST => return Err(AppError::bad_request("Invalid image request")),
+			status => {
+				warn!(
+					"Fetching image received status {}: {}",
+					status,
+					response.text().await.unwrap_or("(unknown)".to_string())
+				);
+				return Err(AppError::internal("Error fetching image"));
+			}
+		}
+		let content_type = response
+			.headers()
+			.get(header::CONTENT_TYPE)
+			.and_then(|c| c.to_str().ok())
+			.unwrap_or("image/webp")
+			.to_string();
+		Ok(ImageData {
+			content_type,
+			content_length: response.content_length(),
+			body: Box::pin(response.bytes_stream().map_err(std::io::Error::other)),
+		})
+	}
+}
+
+#[async_trait]
+impl ImageStorage for PictrsStorage {
+	async fn upload(&self, image_bytes: &Bytes, file_name: &str) -> AppResult<StoredImage> {
+		let file_part =
+			reqwest::multipart::Part::bytes(image_bytes.to_vec()).file_name(file_name.to_string());
+		let form = reqwest::multipart::Form::new().part("images[]", file_part);
+		info!("Uploading image {}", file_name);
+		let response = reqwest::Client::new()
+			.post(format!("{}/image", self.url))
+			.multipart(form)
+			.send()
+			.await
+			.map_err(|_| AppError::bad_request("Invalid image"))?;
+		if !response.status().is_success() {
+			warn!(
+				"Image upload received status {}: {}",
+				response.status(),
+				response.text().await.unwrap_or("(unknown)".to_string())
+			);
+			return Err(AppError::bad_request("Invalid image"));
+		}
+		let response = response
+			.json::<ImageUploadResponse>()
+			.await
+			.map_err(|_| AppError::internal("Failed to parse image response"))?;
+		if response.msg != "ok" {
+			warn!(
+				"Image upload received incorrect status message: {}",
+				&response.msg
+			);
+			return Err(AppError::bad_request("Error uploading image"));
+		}
+		if response.files.len() != 1 {
+			return Err(AppError::internal("Image upload failed"));
+		}
+		let uploaded = response.files.first().unwrap();
+		let id = Uuid::parse_str(
+			uploaded
+				.file
+				.split_once('.')
+				.ok_or(AppError::internal("Image upload failed"))?
+				.0,
+		)
+		.map_err(|_| AppError::internal("Image upload failed"))?;
+		let delete_token = Uuid::parse_str(&uploaded.delete_token)
+			.map_err(|_| AppError::internal("Image upload failed"))?;
+		info!("Image uploaded successfully");
+		Ok(StoredImage {
+			id,
+			delete_token,
+			width: uploaded.details.width,
+			height: uploaded.details.height,
+		})
+	}
+
+	async fn original(&self, id: &Uuid) -> AppResult<ImageData> {
+		self.fetch(&format!("{}/image/original/{}.webp", self.url, id))
+			.await
+	}
+
+	async fn thumbnail(&self, id: &Uuid, variant: ThumbnailVariant) -> AppResult<ImageData> {
+		// pict-rs applies filters in the order given, so the crop comes before the resize
+		let crop = match variant.square {
+			true => "crop=1x1&",
+			false => "",
+		};
+		self.fetch(&format!(
+			"{}/image/process.webp?{}thumbnail={}&src={}.webp",
+			self.url, crop, variant.size, id
+		))
 		.await
+	}
+
+	async fn delete(&self, id: &Uuid, delete_token: &Uuid) -> AppResult<()> {
+		let delete_url = format!("{}/image/delete/{}/{}.webp", self.url, delete_token, id);
+		info!("Deleting image {}", id);
+		let response = reqwest::Client::new()
+			.delete(&delete_url)
+			.send()
+			.await
+			.map_err(|_| AppError::internal("Error deleting image"))?;
+		if !response.status().is_success() && response.status() != reqwest::StatusCode::NOT_FOUND {
+			warn!(
+				"Image deletion received status {}: {}",
+				response.status(),
+				response.text().await.unwrap_or("(unknown)".to_string())
+			);
+			return Err(AppError::internal("Error deleting image"));
+		}
+		Ok(())
+	}
+}
+
+/// Keeps images in a directory, converting and thumbnailing them itself. Only the first
+/// frame of animated images is kept.
+pub struct LocalStorage {
+	dir: PathBuf,
+}
+
+impl LocalStorage {
+	pub fn new(dir: PathBuf) -> AppResult<Self> {
+		std::fs::create_dir_all(dir.join("thumbnails"))
+			.map_err(|_| AppError::internal("Failed to create image directory"))?;
+		Ok(Self { dir })
+	}
+
+	fn original_path(&self, id: &Uuid) -> PathBuf {
+		self.dir.join(format!("{}.webp", id))
+	}
+
+	fn thumbnail_path(&self, id: &Uuid, variant: ThumbnailVariant) -> PathBuf {
+		self.dir
+			.join("thumbnails")
+			.join(format!("{}-{}.webp", id, variant.name()))
+	}
+
+	/// Writes through a temporary file, so readers never see half an image
+	async fn write(path: &PathBuf, data: &[u8]) -> AppResult<()> {
+		let temporary = path.with_extension(format!("{}.tmp", Uuid::new_v4()));
+		tokio::fs::write(&temporary, data)
+			.await
+			.map_err(|_| AppError::internal("Failed to store image"))?;
+		tokio::fs::rename(&temporary, path)
+			.await
+			.map_err(|_| AppError::internal("Failed to store image"))
+	}
+
+	async fn read(path: &PathBuf) -> AppResult<ImageData> {
+		let file = tokio::fs::File::open(path)
+			.await
+			.map_err(|e| match e.kind() {
+				std::io::ErrorKind::NotFound => AppError::not_found("Image not found"),
+				_ => AppError::internal("Error fetching image"),
+			})?;
+		let content_length = file.metadata().await.ok().map(|m| m.len());
+		Ok(ImageData {
+			content_type: "image/webp".to_string(),
+			content_length,
+			body: Box::pin(ReaderStream::new(file)),
+		})
+	}
+}
+
+fn decode(data: &[u8]) -> AppResult<DynamicImage> {
+	let mut reader = ImageReader::new(Cursor::new(data))
+		.with_guessed_format()
 		.map_err(|_| AppError::bad_request("Invalid image"))?;
-	if !response.status().is_success() {
-		warn!(
-			"Image upload received status {}: {}",
-			response.status(),
-			response.text().await.unwrap_or("(unknown)".to_string())
-		);
-		return Err(AppError::bad_request("Invalid image"));
+	// Keeps small uploads from decompressing into huge images
+	let mut limits = Limits::default();
+	limits.max_image_width = Some(16384);
+	limits.max_image_height = Some(16384);
+	reader.limits(limits);
+	reader
+		.decode()
+		.map_err(|_| AppError::bad_request("Invalid image"))
+}
+
+fn encode_webp(image: &DynamicImage) -> AppResult<Vec<u8>> {
+	// The WebP encoder only takes 8 bit images
+	let image = match image.color().has_alpha() {
+		true => DynamicImage::ImageRgba8(image.to_rgba8()),
+		false => DynamicImage::ImageRgb8(image.to_rgb8()),
+	};
+	let mut encoded = Cursor::new(Vec::new());
+	image
+		.write_to(&mut encoded, ImageFormat::WebP)
+		.map_err(|_| AppError::internal("Failed to convert image"))?;
+	Ok(encoded.into_inner())
+}
+
+#[async_trait]
+impl ImageStorage for LocalStorage {
+	async fn upload(&self, image_bytes: &Bytes, file_name: &str) -> AppResult<StoredImage> {
+		info!("Storing image {}", file_name);
+		let data = image_bytes.clone();
+		// Decoding and encoding take a while, so keep them off the async workers
+		let (encoded, width, height) = tokio::task::spawn_blocking(move || {
+			let image = decode(&data)?;
+			Ok::<_, AppError>((encode_webp(&image)?, image.width(), image.height()))
+		})
+		.await
+		.map_err(|_| AppError::internal("Failed to convert image"))??;
+		let id = Uuid::new_v4();
+		Self::write(&self.original_path(&id), &encoded).await?;
+		Ok(StoredImage {
+			id,
+			// Only the database knows about images here, so there is nothing to check this against
+			delete_token: Uuid::new_v4(),
+			width,
+			height,
+		})
+	}
+
+	async fn original(&self, id: &Uuid) -> AppResult<ImageData> {
+		Self::read(&self.original_path(id)).await
 	}
-	let response = response
-		.json::<ImageUploadResponse>()
+
+	/// Thumbnails are made on first use and kept
+	async fn thumbnail(&self, id: &Uuid, variant: ThumbnailVariant) -> AppResult<ImageData> {
+		let path = self.thumbnail_path(id, variant);
+		if let Ok(data) = Self::read(&path).await {
+			return Ok(data);
+		}
+		let original = self.original(id).await?.into_bytes().await?;
+		let thumbnail = tokio::task::spawn_blocking(move || {
+			let image = decode(&original)?;
+			// Like with pict-rs, small images are never enlarged
+			let size = variant.size;
+			let thumbnail = match variant.square {
+				true => {
+					let side = size.min(image.width()).min(image.height());
+					image.resize_to_fill(side, side, FilterType::Triangle)
+				}
+				false if image.width() <= size && image.height() <= size => {
+					return Ok(original.to_vec());
+				}
+				false => image.thumbnail(size, size),
+			};
+			encode_webp(&thumbnail)
+		})
 		.await
-		.map_err(|_| AppError::internal("Failed to parse image response"))?;
-	if response.msg != "ok" {
-		warn!(
-			"Image upload received incorrect status message: {}",
-			&response.msg
+		.map_err(|_| AppError::internal("Failed to create thumbnail"))??;
+		Self::write(&path, &thumbnail).await?;
+		Ok(ImageData::from_bytes("image/webp", thumbnail.into()))
+	}
+
+	async fn delete(&self, id: &Uuid, _delete_token: &Uuid) -> AppResult<()> {
+		info!("Deleting image {}", id);
+		let thumbnails = ThumbnailVariant::all().map(|variant| self.thumbnail_path(id, variant));
+		for path in thumbnails.chain([self.original_path(id)]) {
+			match tokio::fs::remove_file(&path).await {
+				Ok(()) => {}
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+				Err(_) => return Err(AppError::internal("Error deleting image")),
+			}
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use axum::{http::StatusCode, response::IntoResponse};
+	use image::RgbImage;
+
+	use super::*;
+
+	fn png(width: u32, height: u32) -> Bytes {
+		let mut encoded = Cursor::new(Vec::new());
+		RgbImage::from_pixel(width, height, image::Rgb([200, 100, 50]))
+			.write_to(&mut encoded, ImageFormat::Png)
+			.unwrap();
+		encoded.into_inner().into()
+	}
+
+	/// Checks that the data is a WebP image and returns its size
+	async fn webp_size(data: ImageData) -> (u32, u32) {
+		assert_eq!(data.content_type, "image/webp");
+		let length = data.content_length;
+		let bytes = data.into_bytes().await.unwrap();
+		assert_eq!(length, Some(bytes.len() as u64));
+		let image = image::load_from_memory_with_format(&bytes, ImageFormat::WebP).unwrap();
+		(image.width(), image.height())
+	}
+
+	fn status<T>(result: AppResult<T>) -> StatusCode {
+		match result {
+			Ok(_) => panic!("expected an error"),
+			Err(e) => e.into_response().status(),
+		}
+	}
+
+	#[tokio::test]
+	async fn local_storage_round_trip() {
+		let dir = tempfile::tempdir().unwrap();
+		let storage = LocalStorage::new(dir.path().to_path_buf()).unwrap();
+
+		let stored = storage.upload(&png(900, 600), "photo.png").await.unwrap();
+		assert_eq!((stored.width, stored.height), (900, 600));
+		assert_eq!(
+			webp_size(storage.original(&stored.id).await.unwrap()).await,
+			(900, 600)
+		);
+		let variant = ThumbnailVariant::default();
+		assert_eq!(
+			webp_size(storage.thumbnail(&stored.id, variant).await.unwrap()).await,
+			(512, 341)
+		);
+		// The second time it comes from the cached file
+		assert_eq!(
+			webp_size(storage.thumbnail(&stored.id, variant).await.unwrap()).await,
+			(512, 341)
+		);
+
+		storage
+			.delete(&stored.id, &stored.delete_token)
+			.await
+			.unwrap();
+		assert_eq!(
+			status(storage.original(&stored.id).await),
+			StatusCode::NOT_FOUND
+		);
+		assert_eq!(
+			status(storage.thumbnail(&stored.id, variant).await),
+			StatusCode::NOT_FOUND
+		);
+		// Deleting again is fine, and nothing is left behind
+		storage
+			.delete(&stored.id, &stored.delete_token)
+			.await
+			.unwrap();
+		assert_eq!(
+			std::fs::read_dir(dir.path().join("thumbnails"))
+				.unwrap()
+				.count(),
+			0
+		);
+		assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+	}
+
+	#[tokio::test]
+	async fn local_storage_thumbnail_variants() {
+		let dir = tempfile::tempdir().unwrap();
+		let storage = LocalStorage::new(dir.path().to_path_buf()).unwrap();
+		let stored = storage.upload(&png(300, 200), "small.png").await.unwrap();
+		for (size, square, expected) in [
+			(128, false, (128, 85)),
+			(256, false, (256, 171)),
+			(512, false, (300, 200)),
+			(1024, false, (300, 200)),
+			(128, true, (128, 128)),
+			(1024, true, (200, 200)),
+		] {
+			let variant = ThumbnailVariant::new(size, square).unwrap();
+			let thumbnail = storage.thumbnail(&stored.id, variant).await.unwrap();
+			assert_eq!(
+				webp_size(thumbnail).await,
+				expected,
+				"variant {}",
+				variant.name()
+			);
+		}
+	}
+
+	#[test]
+	fn thumbnail_sizes_are_limited() {
+		assert!(ThumbnailVariant::new(256, true).is_ok());
+		assert!(ThumbnailVariant::new(300, false).is_err());
+		assert!(ThumbnailVariant::new(0, false).is_err());
+	}
+
+	#[tokio::test]
+	async fn local_storage_rejects_non_images() {
+		let dir = tempfile::tempdir().unwrap();
+		let storage = LocalStorage::new(dir.path().to_path_buf()).unwrap();
+		let upload = storage
+			.upload(&Bytes::from_static(b"not an image"), "notes.txt")
+			.await;
+		assert_eq!(status(upload), StatusCode::BAD_REQUEST);
+		let truncated = png(100, 100).slice(..40);
+		assert_eq!(
+			status(storage.upload(&truncated, "broken.png").await),
+			StatusCode::BAD_REQUEST
+		);
+	}
+
+	#[tokio::test]
+	async fn local_storage_unknown_image() {
+		let dir = tempfile::tempdir().unwrap();
+		let storage = LocalStorage::new(dir.path().to_path_buf()).unwrap();
+		assert_eq!(
+			status(storage.original(&Uuid::new_v4()).await),
+			StatusCode::NOT_FOUND
 		);
-		return Err(AppError::bad_request("Error uploading image"));
 	}
-	info!("Image uploaded successfully");
-	Ok(response)
 }
@@ -1 +1,3 @@
 pub mod image;
+pub mod mail;
+pub mod oidc;
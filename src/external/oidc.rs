@@ -0,0 +1,363 @@
+use std::{
+	sync::{Arc, Mutex},
+	time::{Duration, Instant},
+};
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use reqwest::Url;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tracing::{info, warn};
+
+use crate::error::{AppError, AppResult};
+
+pub struct OidcConfig {
+	pub issuer: String,
+	pub client_id: String,
+	pub client_secret: Option<String>,
+	/// Where the provider sends users back to, normally a page of the frontend
+	pub redirect_url: String,
+	/// Whether to create accounts for unknown identities
+	pub auto_provision: bool,
+}
+
+/// How long a discovery document is used before it is fetched again
+const DISCOVERY_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Deserialize)]
+pub struct ProviderMetadata {
+	issuer: String,
+	authorization_endpoint: String,
+	token_endpoint: String,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+	id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct IdTokenClaims {
+	pub iss: String,
+	pub sub: String,
+	/// Either a single client id or a list of them
+	aud: serde_json::Value,
+	exp: i64,
+	nonce: Option<String>,
+	pub email: Option<String>,
+	#[serde(default)]
+	pub email_verified: bool,
+	pub preferred_username: Option<String>,
+}
+
+async fn discover(issuer: &str) -> AppResult<ProviderMetadata> {
+	let url = format!(
+		"{}/.well-known/openid-configuration",
+		issuer.trim_end_matches('/')
+	);
+	let metadata: ProviderMetadata = reqwest::get(&url)
+		.await
+		.and_then(|r| r.error_for_status())
+		.map_err(|e| {
+			warn!("Failed to fetch OIDC discovery document: {}", e);
+			AppError::internal("Identity provider is unavailable")
+		})?
+		.json()
+		.await
+		.map_err(|_| AppError::internal("Invalid identity provider configuration"))?;
+	if metadata.issuer.trim_end_matches('/') != issuer.trim_end_matches('/') {
+		return Err(AppError::internal("Identity provider issuer mismatch"));
+	}
+	Ok(metadata)
+}
+
+/// Keeps the discovery document of the provider, so it is not fetched for every login
+#[derive(Default)]
+pub struct DiscoveryCache {
+	cached: Mutex<Option<(Instant, Arc<ProviderMetadata>)>>,
+}
+
+impl DiscoveryCache {
+	pub async fn get(&self, issuer: &str) -> AppResult<Arc<ProviderMetadata>> {
+		if let Some((fetched_at, metadata)) = &*self.cached.lock().unwrap() {
+			if fetched_at.elapsed() < DISCOVERY_TTL {
+				return Ok(metadata.clone());
+			}
+		}
+		let metadata = Arc::new(discover(issuer).await?);
+		*self.cached.lock().unwrap() = Some((Instant::now(), metadata.clone()));
+		Ok(metadata)
+	}
+}
+
+/// The PKCE S256 challenge for a verifier
+fn code_challenge(verifier: &str) -> String {
+	URL_SAFE_NO_PAD.encode(Sha256::digest(verifier.as_bytes()))
+}
+
+/// Builds the URL to send users to for logging in at the provider
+pub fn authorization_url(
+	config: &OidcConfig,
+	metadata: &ProviderMetadata,
+	state: &str,
+	nonce: &str,
+	code_verifier: &str,
+) -> AppResult<String> {
+	let mut url = Url::parse(&metadata.authorization_endpoint)
+		.map_err(|_| AppError::internal("Invalid identity provider configuration"))?;
+	url.query_pairs_mut()
+		.append_pair("response_type", "code")
+		.append_pair("client_id", &config.client_id)
+		.append_pair("redirect_uri", &config.redirect_url)
+		.append_pair("scope", "openid email profile")
+		.append_pair("state", state)
+		.append_pair("nonce", nonce)
+		.append_pair("code_challenge", &code_challenge(code_verifier))
+		.append_pair("code_challenge_method", "S256");
+	Ok(url.to_string())
+}
+
+/// Redeems an authorization code and returns the validated claims of the ID token
+pub async fn exchange_code(
+	config: &OidcConfig,
+	metadata: &ProviderMetadata,
+	code: &str,
+	code_verifier: &str,
+	nonce: &str,
+) -> AppResult<IdTokenClaims> {
+	let mut form = vec![
+		("grant_type", "authorization_code"),
+		("code", code),
+		("redirect_uri", &config.redirect_url),
+		("client_id", &config.client_id),
+		("code_verifier", code_verifier),
+	];
+	if let Some(secret) = &config.client_secret {
+		form.push(("client_secret", secret));
+	}
+	let response = reqwest::Client::new()
+		.post(&metadata.token_endpoint)
+		.form(&form)
+		.send()
+		.await
+		.map_err(|_| AppError::internal("Identity provider is unavailable"))?;
+	if !response.status().is_success() {
+		warn!(
+			"OIDC token request received status {}: {}",
+			response.status(),
+			response.text().await.unwrap_or("(unknown)".to_string())
+		);
+		return Err(AppError::unauthorized("Login at identity provider failed"));
+	}
+	let tokens: TokenResponse = response
+		.json()
+		.await
+		.map_err(|_| AppError::internal("Invalid identity provider response"))?;
+
+	// The ID token comes straight from the token endpoint, so its signature does not need to be
+	// checked (OpenID Connect Core 3.1.3.7), but its claims do
+	let claims: IdTokenClaims = tokens
+		.id_token
+		.split('.')
+		.nth(1)
+		.and_then(|payload| URL_SAFE_NO_PAD.decode(payload.trim_end_matches('=')).ok())
+		.and_then(|payload| serde_json::from_slice(&payload).ok())
+		.ok_or(AppError::internal("Invalid ID token"))?;
+	let audience_matches = match &claims.aud {
+		serde_json::Value::String(aud) => aud == &config.client_id,
+		serde_json::Value::Array(auds) => auds.iter().any(|a| a == config.client_id.as_str()),
+		_ => false,
+	};
+	if claims.iss.trim_end_matches('/') != config.issuer.trim_end_matches('/')
+		|| !audience_matches
+		|| claims.exp < chrono::Utc::now().timestamp()
+		|| claims.nonce.as_deref() != Some(nonce)
+	{
+		warn!("Rejected OIDC ID token for subject {}", claims.sub);
+		return Err(AppError::unauthorized("Invalid ID token"));
+	}
+	info!("OIDC login for subject {}", claims.sub);
+	Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{
+		net::TcpListener,
+		sync::atomic::{AtomicUsize, Ordering},
+	};
+
+	use axum::{
+		http::StatusCode,
+		response::IntoResponse,
+		routing::{get, post},
+		Json, Router,
+	};
+	use serde_json::json;
+
+	use super::*;
+
+	const NONCE: &str = "nonce";
+
+	/// A provider whose token endpoint hands out an ID token with `claims`. The issuer is
+	/// filled in if `claims` leaves it out.
+	struct MockProvider {
+		config: OidcConfig,
+		discoveries: Arc<AtomicUsize>,
+	}
+
+	impl MockProvider {
+		fn start(claims: serde_json::Value) -> Self {
+			let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+			let issuer = format!("http://{}", listener.local_addr().unwrap());
+			let discoveries = Arc::new(AtomicUsize::new(0));
+			let mut claims = claims;
+			if claims.get("iss").is_none() {
+				claims["iss"] = json!(issuer);
+			}
+			let metadata = json!({
+				"issuer": issuer,
+				"authorization_endpoint": format!("{}/authorize", issuer),
+				"token_endpoint": format!("{}/token", issuer),
+			});
+			let counter = discoveries.clone();
+			let router = Router::new()
+				.route(
+					"/.well-known/openid-configuration",
+					get(move || async move {
+						counter.fetch_add(1, Ordering::SeqCst);
+						Json(metadata)
+					}),
+				)
+				.route(
+					"/token",
+					post(move |form: axum::Form<Vec<(String, String)>>| async move {
+						if !form.0.contains(&("code".to_string(), "good".to_string())) {
+							return StatusCode::BAD_REQUEST.into_response();
+						}
+						let payload = URL_SAFE_NO_PAD.encode(claims.to_string());
+						Json(json!({ "id_token": format!("header.{}.signature", payload) }))
+							.into_response()
+					}),
+				);
+			tokio::spawn(
+				axum::Server::from_tcp(listener)
+					.unwrap()
+					.serve(router.into_make_service()),
+			);
+			MockProvider {
+				config: OidcConfig {
+					issuer,
+					client_id: "cromptch".to_string(),
+					client_secret: None,
+					redirect_url: "http://localhost:3000/oidc".to_string(),
+					auto_provision: false,
+				},
+				discoveries,
+			}
+		}
+
+		async fn exchange(&self, code: &str) -> AppResult<IdTokenClaims> {
+			let metadata = discover(&self.config.issuer).await?;
+			exchange_code(&self.config, &metadata, code, "verifier", NONCE).await
+		}
+	}
+
+	fn valid_claims() -> serde_json::Value {
+		json!({
+			"sub": "subject",
+			"aud": "cromptch",
+			"exp": chrono::Utc::now().timestamp() + 300,
+			"nonce": NONCE,
+			"email": "someone@example.com",
+			"email_verified": true,
+		})
+	}
+
+	fn status(result: AppResult<IdTokenClaims>) -> StatusCode {
+		result.unwrap_err().into_response().status()
+	}
+
+	#[tokio::test]
+	async fn discovery_is_cached() {
+		let provider = MockProvider::start(valid_claims());
+		let cache = DiscoveryCache::default();
+		cache.get(&provider.config.issuer).await.unwrap();
+		cache.get(&provider.config.issuer).await.unwrap();
+		assert_eq!(provider.discoveries.load(Ordering::SeqCst), 1);
+	}
+
+	#[tokio::test]
+	async fn discovery_rejects_other_issuer() {
+		let provider = MockProvider::start(valid_claims());
+		let issuer = provider.config.issuer.replace("127.0.0.1", "localhost");
+		assert!(discover(&issuer).await.is_err());
+	}
+
+	#[tokio::test]
+	async fn authorization_url_uses_pkce() {
+		let provider = MockProvider::start(valid_claims());
+		let metadata = discover(&provider.config.issuer).await.unwrap();
+		let url =
+			authorization_url(&provider.config, &metadata, "state", NONCE, "verifier").unwrap();
+		let url = Url::parse(&url).unwrap();
+		let query: Vec<(String, String)> = url.query_pairs().into_owned().collect();
+		assert!(url.as_str().starts_with(&metadata.authorization_endpoint));
+		for (key, value) in [
+			("state", "state"),
+			("nonce", NONCE),
+			("client_id", "cromptch"),
+			("code_challenge_method", "S256"),
+		] {
+			assert!(query.contains(&(key.to_string(), value.to_string())));
+		}
+		assert!(query.contains(&("code_challenge".to_string(), code_challenge("verifier"))));
+	}
+
+	#[tokio::test]
+	async fn exchange_returns_claims() {
+		let provider = MockProvider::start(valid_claims());
+		let claims = provider.exchange("good").await.unwrap();
+		assert_eq!(claims.sub, "subject");
+		assert_eq!(claims.email.as_deref(), Some("someone@example.com"));
+		assert!(claims.email_verified);
+	}
+
+	#[tokio::test]
+	async fn exchange_accepts_audience_lists() {
+		let mut claims = valid_claims();
+		claims["aud"] = json!(["other", "cromptch"]);
+		let provider = MockProvider::start(claims);
+		assert!(provider.exchange("good").await.is_ok());
+	}
+
+	#[tokio::test]
+	async fn exchange_rejects_bad_code() {
+		let provider = MockProvider::start(valid_claims());
+		assert_eq!(
+			status(provider.exchange("bad").await),
+			StatusCode::UNAUTHORIZED
+		);
+	}
+
+	#[tokio::test]
+	async fn exchange_rejects_invalid_claims() {
+		let changes = [
+			("nonce", json!("replayed")),
+			("aud", json!("other")),
+			("exp", json!(chrono::Utc::now().timestamp() - 10)),
+			("iss", json!("https://elsewhere.example.com")),
+		];
+		for (claim, value) in changes {
+			let mut claims = valid_claims();
+			claims[claim] = value;
+			let provider = MockProvider::start(claims);
+			assert_eq!(
+				status(provider.exchange("good").await),
+				StatusCode::UNAUTHORIZED,
+				"accepted a token with a wrong {}",
+				claim
+			);
+		}
+	}
+}
@@ -2,10 +2,15 @@ mod api;
 mod error;
 mod external;
 mod models;
+mod tasks;
 
 use std::env;
+use std::path::PathBuf;
 use std::str::FromStr;
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+	net::{IpAddr, SocketAddr},
+	sync::Arc,
+};
 
 use axum::extract::DefaultBodyLimit;
 use axum::{
@@ -17,15 +22,30 @@ use sqlx::PgPool;
 use tower_http::cors::CorsLayer;
 use tracing::info;
 
+use crate::external::image::{ImageStorage, LocalStorage, PictrsStorage};
+use crate::external::mail::{FileTransport, MailTransport, SmtpTransport};
+use crate::external::oidc::{DiscoveryCache, OidcConfig};
+use crate::models::session::SessionExpiry;
+
 pub struct Config {
 	pub hcaptcha_site_key: String,
 	pub hcaptcha_secret: String,
-	pub pictrs_url: String,
+	pub frontend_url: String,
+	pub session_expiry: SessionExpiry,
+	pub require_admin_two_factor: bool,
+	pub oidc: Option<OidcConfig>,
+	/// Hours an unused image is kept before it counts as abandoned
+	pub image_gc_grace_hours: i32,
+	/// Reverse proxies whose forwarding headers are believed
+	pub trusted_proxies: Vec<IpAddr>,
 }
 
 pub struct AppState {
 	pub pool: PgPool,
 	pub secrets: Config,
+	pub mailer: Box<dyn MailTransport>,
+	pub images: Arc<dyn ImageStorage>,
+	pub oidc_discovery: DiscoveryCache,
 }
 
 #[tokio::main]
@@ -35,18 +55,82 @@ async fn main() {
 	info!("Starting server...");
 	dotenv::dotenv().ok();
 
+	let frontend_url = env::var("FRONTEND_URL").unwrap_or("http://localhost:3000".to_string());
 	let secrets = Config {
 		hcaptcha_site_key: env::var("HCAPTCHA_SITE_KEY").unwrap_or("".to_string()),
 		hcaptcha_secret: env::var("HCAPTCHA_SECRET").unwrap_or("".to_string()),
-		pictrs_url: env::var("PICTRS_URL").unwrap_or("".to_string()),
+		frontend_url: frontend_url.clone(),
+		session_expiry: SessionExpiry {
+			// Two weeks without use, or three months in total
+			idle_hours: env::var("SESSION_IDLE_HOURS")
+				.map(|h| h.parse().expect("Invalid SESSION_IDLE_HOURS"))
+				.unwrap_or(24 * 14),
+			max_age_hours: env::var("SESSION_MAX_AGE_HOURS")
+				.map(|h| h.parse().expect("Invalid SESSION_MAX_AGE_HOURS"))
+				.unwrap_or(24 * 90),
+		},
+		require_admin_two_factor: env::var("REQUIRE_ADMIN_2FA").is_ok_and(|r| r == "true"),
+		image_gc_grace_hours: env::var("IMAGE_GC_GRACE_HOURS")
+			.map(|h| h.parse().expect("Invalid IMAGE_GC_GRACE_HOURS"))
+			.unwrap_or(48),
+		trusted_proxies: env::var("TRUSTED_PROXIES")
+			.unwrap_or_default()
+			.split(',')
+			.map(str::trim)
+			.filter(|p| !p.is_empty())
+			.map(|p| p.parse().expect("Invalid address in TRUSTED_PROXIES"))
+			.collect(),
+		oidc: env::var("OIDC_ISSUER")
+			.ok()
+			.filter(|i| !i.is_empty())
+			.map(|issuer| OidcConfig {
+				issuer,
+				client_id: env::var("OIDC_CLIENT_ID").expect("OIDC_CLIENT_ID not set"),
+				client_secret: env::var("OIDC_CLIENT_SECRET")
+					.ok()
+					.filter(|s| !s.is_empty()),
+				redirect_url: env::var("OIDC_REDIRECT_URL")
+					.ok()
+					.filter(|u| !u.is_empty())
+					.unwrap_or(format!("{}/oidc", frontend_url)),
+				auto_provision: env::var("OIDC_AUTO_PROVISION").is_ok_and(|a| a == "true"),
+			}),
 	};
 	if secrets.hcaptcha_site_key.is_empty() {
 		info!("HCAPTCHA_SITE_KEY not set, captcha will not be used");
 	}
-	if secrets.pictrs_url.is_empty() {
-		info!("PICTRS_URL not set, image features will be disabled");
+	if secrets.oidc.is_none() {
+		info!("OIDC_ISSUER not set, single sign-on will be disabled");
 	}
-	let frontend_url = env::var("FRONTEND_URL").unwrap_or("http://localhost:3000".to_string());
+
+	let mailer: Box<dyn MailTransport> = match env::var("SMTP_URL").ok().filter(|u| !u.is_empty()) {
+		Some(url) => Box::new(
+			SmtpTransport::new(
+				&url,
+				&env::var("MAIL_FROM").unwrap_or("Cromptch <noreply@localhost>".to_string()),
+			)
+			.expect("Invalid mail configuration"),
+		),
+		None => {
+			info!("SMTP_URL not set, mails will be written to MAIL_DIR or the log");
+			Box::new(FileTransport::new(
+				env::var("MAIL_DIR").ok().map(PathBuf::from),
+			))
+		}
+	};
+
+	let images: Arc<dyn ImageStorage> = match env::var("PICTRS_URL").ok().filter(|u| !u.is_empty())
+	{
+		Some(url) => Arc::new(PictrsStorage::new(&url)),
+		None => {
+			let dir = env::var("IMAGE_DIR")
+				.ok()
+				.filter(|d| !d.is_empty())
+				.unwrap_or("images".to_string());
+			info!("PICTRS_URL not set, images will be stored in {}", dir);
+			Arc::new(LocalStorage::new(PathBuf::from(dir)).expect("Invalid image directory"))
+		}
+	};
 
 	info!("Connecting to database...");
 	let pool = PgPool::connect(&env::var("POSTGRES_URL").expect("DATABASE_URL not set"))
@@ -61,18 +145,33 @@ async fn main() {
 		.expect("Migrations failed to run");
 	info!("Database migrations complete!");
 
-	let app_state = Arc::new(AppState { pool, secrets });
+	let app_state = Arc::new(AppState {
+		pool,
+		secrets,
+		mailer,
+		images,
+		oidc_discovery: DiscoveryCache::default(),
+	});
+
+	tasks::spawn_session_purge(app_state.clone());
+	tasks::spawn_login_failure_purge(app_state.clone());
+	tasks::spawn_image_gc(app_state.clone());
 
 	info!("Creating routes...");
 	let router = Router::new()
 		.route("/api", get(index))
 		.merge(api::user::user_router(app_state.clone()))
+		.merge(api::oidc::oidc_router(app_state.clone()))
 		.merge(api::recipe::recipe_router(app_state.clone()))
 		.merge(api::image::image_router(app_state.clone()))
 		.merge(api::admin::admin_router(app_state.clone()))
 		.layer(
 			CorsLayer::new()
-				.allow_origin([frontend_url.parse::<HeaderValue>().unwrap()])
+				.allow_origin([app_state
+					.secrets
+					.frontend_url
+					.parse::<HeaderValue>()
+					.unwrap()])
 				.allow_headers([http::header::AUTHORIZATION, http::header::CONTENT_TYPE])
 				.allow_methods([Method::GET, Method::POST, Method::PUT, Method::DELETE]),
 		)
@@ -86,7 +185,7 @@ async fn main() {
 		u16::from_str(&port).expect("Invalid port number"),
 	));
 	axum::Server::bind(&addr)
-		.serve(router.into_make_service())
+		.serve(router.into_make_service_with_connect_info::<SocketAddr>())
 		.await
 		.expect("Failed to start server");
 }
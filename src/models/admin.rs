@@ -23,7 +23,13 @@ where
 	type Rejection = AppError;
 
 	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-		let user = User::from_request_parts(parts, state).await?;
+		let app_state = Arc::from_ref(state);
+		let user = User::authenticate(parts, &app_state).await?;
+		if user.needs_two_factor_for_admin(&app_state) {
+			return Err(AppError::forbidden(
+				"Admins must enable two-factor authentication",
+			));
+		}
 		user.is_admin
 			.then_some(Admin { user })
 			.ok_or_else(|| AppError::unauthorized("user is not an admin"))
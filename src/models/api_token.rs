@@ -0,0 +1,193 @@
+use std::str::FromStr;
+
+use axum::http::Method;
+use chrono::naive::serde::{ts_seconds, ts_seconds_option};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::chrono::NaiveDateTime, PgPool};
+use uuid::Uuid;
+
+use crate::error::{AppError, AppResult};
+
+use super::user::{generate_token, token_digest, User};
+
+/// Personal API tokens start with this, so they can be told apart from session tokens
+pub const API_TOKEN_PREFIX: &str = "cpat_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Scope {
+	#[serde(rename = "recipe:read")]
+	RecipeRead,
+	#[serde(rename = "recipe:write")]
+	RecipeWrite,
+	#[serde(rename = "image:write")]
+	ImageWrite,
+	#[serde(rename = "admin")]
+	Admin,
+}
+
+impl Scope {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			Scope::RecipeRead => "recipe:read",
+			Scope::RecipeWrite => "recipe:write",
+			Scope::ImageWrite => "image:write",
+			Scope::Admin => "admin",
+		}
+	}
+
+	/// The scope a token needs for a request. Routes without one, like account
+	/// management, can only be used with a session.
+	pub fn required_for(method: &Method, path: &str) -> Option<Scope> {
+		let read = method == Method::GET || method == Method::HEAD;
+		if path.starts_with("/api/admin/") {
+			Some(Scope::Admin)
+		} else if path.starts_with("/api/recipe/") {
+			Some(match read {
+				true => Scope::RecipeRead,
+				false => Scope::RecipeWrite,
+			})
+		} else if path.starts_with("/api/image") && !read {
+			Some(Scope::ImageWrite)
+		} else {
+			None
+		}
+	}
+}
+
+impl FromStr for Scope {
+	type Err = AppError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"recipe:read" => Ok(Scope::RecipeRead),
+			"recipe:write" => Ok(Scope::RecipeWrite),
+			"image:write" => Ok(Scope::ImageWrite),
+			"admin" => Ok(Scope::Admin),
+			_ => Err(AppError::bad_request(format!("Unknown scope {}", s))),
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ApiToken {
+	pub id: Uuid,
+	pub name: String,
+	pub scopes: Vec<Scope>,
+	#[serde(with = "ts_seconds")]
+	pub created_at: NaiveDateTime,
+	#[serde(with = "ts_seconds_option")]
+	pub last_used: Option<NaiveDateTime>,
+	#[serde(with = "ts_seconds_option")]
+	pub expires_at: Option<NaiveDateTime>,
+}
+
+struct ApiTokenRow {
+	id: Uuid,
+	name: String,
+	scopes: Vec<String>,
+	created_at: NaiveDateTime,
+	last_used: Option<NaiveDateTime>,
+	expires_at: Option<NaiveDateTime>,
+}
+
+impl From<ApiTokenRow> for ApiToken {
+	fn from(row: ApiTokenRow) -> Self {
+		ApiToken {
+			id: row.id,
+			name: row.name,
+			// Only valid scopes are ever stored
+			scopes: row.scopes.iter().filter_map(|s| s.parse().ok()).collect(),
+			created_at: row.created_at,
+			last_used: row.last_used,
+			expires_at: row.expires_at,
+		}
+	}
+}
+
+impl ApiToken {
+	/// Creates a token for the user. Returns the token itself, which is only ever stored as a digest.
+	pub async fn create(
+		pool: &PgPool,
+		user: &User,
+		name: &str,
+		scopes: &[Scope],
+		expires_in_days: Option<i32>,
+	) -> AppResult<(ApiToken, String)> {
+		let token = format!("{}{}", API_TOKEN_PREFIX, generate_token());
+		let scopes: Vec<String> = scopes.iter().map(|s| s.as_str().to_string()).collect();
+		let row = sqlx::query_as!(
+			ApiTokenRow,
+			r#"
+			INSERT INTO api_tokens (user_id, name, token_hash, scopes, expires_at)
+			VALUES ($1, $2, $3, $4, NOW() + make_interval(days => $5))
+			RETURNING id, name, scopes, created_at, last_used, expires_at
+			"#,
+			user.id,
+			name,
+			token_digest(&token),
+			&scopes,
+			expires_in_days
+		)
+		.fetch_one(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to create token"))?;
+		Ok((row.into(), token))
+	}
+
+	pub async fn list(pool: &PgPool, user_id: &Uuid) -> AppResult<Vec<ApiToken>> {
+		let rows = sqlx::query_as!(
+			ApiTokenRow,
+			r#"
+			SELECT id, name, scopes, created_at, last_used, expires_at
+			FROM api_tokens
+			WHERE user_id = $1
+			ORDER BY created_at
+			"#,
+			user_id
+		)
+		.fetch_all(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to get tokens"))?;
+		Ok(rows.into_iter().map(ApiToken::from).collect())
+	}
+
+	pub async fn delete(pool: &PgPool, user_id: &Uuid, id: &Uuid) -> AppResult<()> {
+		let result = sqlx::query!(
+			r#"
+			DELETE FROM api_tokens
+			WHERE user_id = $1 AND id = $2
+			"#,
+			user_id,
+			id
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to delete token"))?;
+		if result.rows_affected() == 0 {
+			return Err(AppError::not_found("Token not found"));
+		}
+		Ok(())
+	}
+
+	/// Finds the owner of a token and what the token may be used for
+	pub async fn authenticate(pool: &PgPool, token: &str) -> AppResult<(User, Vec<Scope>)> {
+		let digest = token_digest(token);
+		let row = sqlx::query!(
+			r#"
+			UPDATE api_tokens
+			SET last_used = NOW()
+			WHERE token_hash = $1 AND (expires_at IS NULL OR expires_at > NOW())
+			RETURNING user_id, scopes
+			"#,
+			digest
+		)
+		.fetch_optional(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to check token"))?
+		.ok_or(AppError::unauthorized("Invalid API token"))?;
+		let user = User::from_uuid(pool, &row.user_id).await?;
+		let scopes = row.scopes.iter().filter_map(|s| s.parse().ok()).collect();
+		Ok((user, scopes))
+	}
+}
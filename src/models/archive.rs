@@ -0,0 +1,271 @@
+use std::{
+	collections::{HashMap, HashSet, VecDeque},
+	io::Read,
+	sync::Arc,
+};
+
+use bytes::Bytes;
+use futures_util::Stream;
+use serde::{Deserialize, Serialize};
+use sqlx::PgPool;
+use tracing::warn;
+use uuid::Uuid;
+
+use crate::{
+	error::{AppError, AppResult},
+	external::image::ImageStorage,
+};
+
+use super::{
+	image::Image,
+	recipe::{Recipe, RecipeCreation},
+};
+
+/// Bumped whenever the layout of exported archives changes
+pub const ARCHIVE_VERSION: u32 = 1;
+
+/// Describes the contents of an archive. Stored as `manifest.json`, next to
+/// `recipes/<id>.json` for every recipe and `images/<id>.webp` for every image.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveManifest {
+	pub version: u32,
+	pub recipes: Vec<Uuid>,
+}
+
+#[derive(Debug, Default)]
+pub struct ArchiveContents {
+	/// Recipes keyed by their id on the exporting instance
+	pub recipes: Vec<(Uuid, RecipeCreation)>,
+	pub images: HashMap<Uuid, Bytes>,
+}
+
+/// Maps ids from the archive to the ids of the newly created entries
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveImport {
+	pub recipes: HashMap<Uuid, Uuid>,
+	pub images: HashMap<Uuid, Uuid>,
+}
+
+/// Writes an archive one entry at a time, so large collections never have to be held in memory
+pub struct ArchiveExport {
+	pool: PgPool,
+	storage: Arc<dyn ImageStorage>,
+	manifest: Option<ArchiveManifest>,
+	recipes: VecDeque<Uuid>,
+	images: VecDeque<Uuid>,
+	seen_images: HashSet<Uuid>,
+	finished: bool,
+}
+
+impl ArchiveExport {
+	pub fn new(pool: PgPool, storage: Arc<dyn ImageStorage>, recipes: Vec<Uuid>) -> Self {
+		Self {
+			pool,
+			storage,
+			manifest: Some(ArchiveManifest {
+				version: ARCHIVE_VERSION,
+				recipes: recipes.clone(),
+			}),
+			recipes: recipes.into(),
+			images: VecDeque::new(),
+			seen_images: HashSet::new(),
+			finished: false,
+		}
+	}
+
+	pub fn into_stream(self) -> impl Stream<Item = AppResult<Bytes>> {
+		futures_util::stream::unfold(self, |mut export| async move {
+			let entry = export.next_entry().await;
+			// Stop after the first error, the archive is unusable anyway
+			if entry.as_ref().is_some_and(|e| e.is_err()) {
+				export.finished = true;
+			}
+			entry.map(|e| (e, export))
+		})
+	}
+
+	async fn next_entry(&mut self) -> Option<AppResult<Bytes>> {
+		if self.finished {
+			return None;
+		}
+		if let Some(manifest) = self.manifest.take() {
+			let data = serde_json::to_vec_pretty(&manifest).unwrap();
+			return Some(tar_entry("manifest.json", &data));
+		}
+		if let Some(id) = self.images.pop_front() {
+			return Some(self.image_entry(&id).await);
+		}
+		if let Some(id) = self.recipes.pop_front() {
+			return Some(self.recipe_entry(&id).await);
+		}
+		self.finished = true;
+		Some(Ok(tar_end()))
+	}
+
+	async fn recipe_entry(&mut self, id: &Uuid) -> AppResult<Bytes> {
+		// The response has started already, so a recipe deleted meanwhile is left out rather than
+		// ending the archive early
+		let Some(recipe) = Recipe::find(&self.pool, id).await? else {
+			warn!("Leaving deleted recipe {} out of archive", id);
+			return Ok(Bytes::new());
+		};
+		let image_ids = recipe
+			.metadata
+			.image_id
+			.iter()
+			.chain(recipe.steps.iter().filter_map(|s| s.image_id.as_ref()));
+		for image_id in image_ids {
+			if self.seen_images.insert(*image_id) {
+				self.images.push_back(*image_id);
+			}
+		}
+		let data = serde_json::to_vec_pretty(&recipe)
+			.map_err(|_| AppError::internal("Error serializing recipe"))?;
+		tar_entry(&format!("recipes/{}.json", id), &data)
+	}
+
+	async fn image_entry(&mut self, id: &Uuid) -> AppResult<Bytes> {
+		let image = match self.storage.original(id).await {
+			Ok(data) => data.into_bytes().await,
+			Err(e) => Err(e),
+		};
+		match image {
+			Ok(bytes) => tar_entry(&format!("images/{}.webp", id), &bytes),
+			Err(e) => {
+				warn!("Leaving image {} out of archive: {}", id, e);
+				Ok(Bytes::new())
+			}
+		}
+	}
+}
+
+/// Builds a single tar entry, so archives can be streamed one file at a time
+pub fn tar_entry(path: &str, data: &[u8]) -> AppResult<Bytes> {
+	let mut header = tar::Header::new_gnu();
+	header
+		.set_path(path)
+		.map_err(|_| AppError::internal("Invalid archive path"))?;
+	header.set_size(data.len() as u64);
+	header.set_mode(0o644);
+	header.set_mtime(chrono::Utc::now().timestamp() as u64);
+	header.set_cksum();
+
+	let padding = (512 - data.len() % 512) % 512;
+	let mut entry = Vec::with_capacity(512 + data.len() + padding);
+	entry.extend_from_slice(header.as_bytes());
+	entry.extend_from_slice(data);
+	entry.resize(entry.len() + padding, 0);
+	Ok(Bytes::from(entry))
+}
+
+/// Two empty blocks mark the end of a tar archive
+pub fn tar_end() -> Bytes {
+	Bytes::from(vec![0; 1024])
+}
+
+pub fn read_archive(archive: &[u8]) -> AppResult<ArchiveContents> {
+	let invalid = |_| AppError::bad_request("Invalid archive");
+	let mut contents = ArchiveContents::default();
+	let mut manifest: Option<ArchiveManifest> = None;
+	let mut archive = tar::Archive::new(archive);
+	for entry in archive.entries().map_err(invalid)? {
+		let mut entry = entry.map_err(invalid)?;
+		let path = entry.path().map_err(invalid)?.to_string_lossy().to_string();
+		let mut data = Vec::new();
+		entry.read_to_end(&mut data).map_err(invalid)?;
+
+		if path == "manifest.json" {
+			manifest = Some(
+				serde_json::from_slice(&data)
+					.map_err(|_| AppError::bad_request("Invalid archive manifest"))?,
+			);
+		} else if let Some(name) = path.strip_prefix("recipes/") {
+			let recipe: Recipe = serde_json::from_slice(&data)
+				.map_err(|_| AppError::bad_request(format!("Invalid recipe {}", name)))?;
+			contents
+				.recipes
+				.push((recipe.metadata.id, RecipeCreation::from(recipe)));
+		} else if let Some(name) = path.strip_prefix("images/") {
+			let id = name
+				.split_once('.')
+				.and_then(|(id, _)| Uuid::parse_str(id).ok())
+				.ok_or(AppError::bad_request(format!("Invalid image {}", name)))?;
+			contents.images.insert(id, Bytes::from(data));
+		}
+	}
+
+	match manifest {
+		None => Err(AppError::bad_request("Archive has no manifest")),
+		Some(m) if m.version > ARCHIVE_VERSION => Err(AppError::bad_request(
+			"Archive was made by a newer version of Cromptch",
+		)),
+		Some(_) => Ok(contents),
+	}
+}
+
+/// Recreates the recipes of an archive as owned by `owner`, re-uploading their images.
+/// Images that are missing from the archive, or cannot be stored, are dropped from the recipes.
+/// Either every recipe is imported or none are, in which case the uploaded images are removed again.
+pub async fn import(
+	pool: &PgPool,
+	storage: &dyn ImageStorage,
+	contents: ArchiveContents,
+	owner: &Uuid,
+) -> AppResult<ArchiveImport> {
+	let mut images = HashMap::new();
+	let mut uploaded = Vec::new();
+	for (id, bytes) in &contents.images {
+		let file_name = format!("{}.webp", id);
+		match Image::upload(pool, storage, bytes, &file_name, owner).await {
+			Ok(image) => {
+				images.insert(*id, image.id);
+				uploaded.push(image);
+			}
+			Err(e) => warn!("Dropping image {} from imported recipes: {}", id, e),
+		}
+	}
+
+	match insert_recipes(pool, contents.recipes, &images, owner).await {
+		Ok(recipes) => Ok(ArchiveImport { recipes, images }),
+		Err(e) => {
+			for image in uploaded {
+				if let Err(e) = image.delete(pool, storage).await {
+					warn!(
+						"Failed to remove image {} of failed import: {}",
+						image.id, e
+					);
+				}
+			}
+			Err(e)
+		}
+	}
+}
+
+/// Creates the recipes in a single transaction, returning their new ids by their old ones
+async fn insert_recipes(
+	pool: &PgPool,
+	recipes: Vec<(Uuid, RecipeCreation)>,
+	images: &HashMap<Uuid, Uuid>,
+	owner: &Uuid,
+) -> AppResult<HashMap<Uuid, Uuid>> {
+	let remap = |id: Option<Uuid>| id.and_then(|id| images.get(&id).copied());
+	let mut tx = pool
+		.begin()
+		.await
+		.map_err(|_| AppError::internal("Internal db error"))?;
+	let mut created = HashMap::new();
+	for (id, mut recipe) in recipes {
+		recipe.image_id = remap(recipe.image_id);
+		for step in &mut recipe.steps {
+			step.image_id = remap(step.image_id);
+		}
+		let recipe_id = Recipe::insert(&mut tx, owner, &recipe).await?.metadata.id;
+		created.insert(id, recipe_id);
+	}
+	tx.commit()
+		.await
+		.map_err(|_| AppError::internal("Internal db error"))?;
+	Ok(created)
+}
@@ -0,0 +1,106 @@
+use sqlx::PgPool;
+
+use crate::error::{AppError, AppResult};
+
+use super::user::{generate_token, token_digest, User};
+
+/// How long a verification link stays usable
+pub const VERIFICATION_VALIDITY_HOURS: i32 = 24;
+/// Minimum time between two verification mails to the same user
+const RESEND_COOLDOWN_MINUTES: i32 = 5;
+/// Maximum number of verification mails to the same user per day
+const MAX_MAILS_PER_DAY: i64 = 5;
+
+pub struct EmailVerification;
+
+impl EmailVerification {
+	/// Issues a new verification token for the user, unless they have asked for too many lately.
+	/// Returns the token itself, which is only ever stored as a digest.
+	pub async fn create(pool: &PgPool, user: &User) -> AppResult<String> {
+		if user.verified_at.is_some() {
+			return Err(AppError::bad_request("Email address is already verified"));
+		}
+		let recent = sqlx::query!(
+			r#"
+			SELECT
+				COUNT(*) AS "count!",
+				COALESCE(BOOL_OR(created_at > NOW() - make_interval(mins => $2)), FALSE) AS "cooling_down!"
+			FROM email_verifications
+			WHERE user_id = $1 AND created_at > NOW() - INTERVAL '1 day'
+			"#,
+			user.id,
+			RESEND_COOLDOWN_MINUTES
+		)
+		.fetch_one(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to check verification mails"))?;
+		if recent.cooling_down || recent.count >= MAX_MAILS_PER_DAY {
+			return Err(AppError::too_many_requests(
+				"Too many verification mails, please try again later",
+			));
+		}
+
+		let token = generate_token();
+		sqlx::query!(
+			r#"
+			INSERT INTO email_verifications (token_hash, user_id, expires_at)
+			VALUES ($1, $2, NOW() + make_interval(hours => $3))
+			"#,
+			token_digest(&token),
+			user.id,
+			VERIFICATION_VALIDITY_HOURS
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to issue verification token"))?;
+		Ok(token)
+	}
+
+	/// Marks the owner of the token as verified
+	pub async fn redeem(pool: &PgPool, token: &str) -> AppResult<User> {
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Failed to start transaction"))?;
+		let user_id = sqlx::query_scalar!(
+			r#"
+			SELECT user_id
+			FROM email_verifications
+			WHERE token_hash = $1 AND expires_at > NOW()
+			"#,
+			token_digest(token)
+		)
+		.fetch_optional(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to check verification token"))?
+		.ok_or(AppError::bad_request(
+			"Invalid or expired verification token",
+		))?;
+		sqlx::query!(
+			r#"
+			UPDATE users
+			SET verified_at = COALESCE(verified_at, NOW())
+			WHERE id = $1
+			"#,
+			user_id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to verify user"))?;
+		// Any other links sent to the user are no longer needed
+		sqlx::query!(
+			r#"
+			DELETE FROM email_verifications
+			WHERE user_id = $1
+			"#,
+			user_id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to verify user"))?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		User::from_uuid(pool, &user_id).await
+	}
+}
@@ -1,38 +1,314 @@
-use sqlx::PgPool;
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use chrono::naive::serde::ts_seconds;
+use serde::Serialize;
+use sqlx::{types::chrono::NaiveDateTime, PgPool};
+use tracing::warn;
 use uuid::Uuid;
 
-use crate::error::{AppError, AppResult};
+use crate::{
+	error::{AppError, AppResult},
+	external::image::{ImageStorage, StoredImage, ThumbnailVariant},
+};
 
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct Image {
 	pub id: Uuid,
-	#[allow(dead_code)]
-	pub delete_token: Uuid, // TODO
+	/// Needed to delete the image from pict-rs
+	pub delete_token: Uuid,
+	pub owner: Option<Uuid>,
+	/// Unknown for images uploaded before sizes were recorded
+	pub width: Option<i32>,
+	pub height: Option<i32>,
+}
+
+/// What clients need to show an image responsively. URLs are relative to the API root.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageMetadata {
+	pub id: Uuid,
+	pub width: Option<i32>,
+	pub height: Option<i32>,
+	pub url: String,
+	/// Thumbnail URLs by variant name, like `256` or `256-square`
+	pub variants: BTreeMap<String, String>,
+}
+
+/// An image that nothing uses anymore
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanedImage {
+	pub id: Uuid,
+	#[serde(skip)]
+	pub delete_token: Uuid,
+	pub owner: Option<Uuid>,
+	#[serde(with = "ts_seconds")]
+	pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OrphanCollection {
+	pub dry_run: bool,
+	/// Everything that was, or in a dry run would have been, deleted
+	pub candidates: Vec<OrphanedImage>,
+	pub deleted: usize,
+	pub failed: usize,
 }
 
 impl Image {
-	pub async fn create(
-		pool: &PgPool,
-		id: &Uuid,
-		delete_token: &Uuid,
-		owner: &Uuid,
-	) -> AppResult<Self> {
+	pub async fn create(pool: &PgPool, stored: &StoredImage, owner: &Uuid) -> AppResult<Self> {
+		let width = stored.width as i32;
+		let height = stored.height as i32;
 		sqlx::query!(
 			r#"
-                INSERT INTO images (id, delete_token, owner)
-                VALUES ($1, $2, $3)
+                INSERT INTO images (id, delete_token, owner, width, height)
+                VALUES ($1, $2, $3, $4, $5)
             "#,
-			id,
-			delete_token,
+			stored.id,
+			stored.delete_token,
 			owner,
+			width,
+			height,
 		)
 		.execute(pool)
 		.await
 		.map_err(|_| AppError::internal("Failed to create image entry"))?;
 
 		Ok(Self {
-			id: *id,
-			delete_token: *delete_token,
+			id: stored.id,
+			delete_token: stored.delete_token,
+			owner: Some(*owner),
+			width: Some(width),
+			height: Some(height),
 		})
 	}
+
+	pub async fn from_uuid(pool: &PgPool, id: &Uuid) -> AppResult<Self> {
+		sqlx::query_as!(
+			Image,
+			r#"
+                SELECT id, delete_token, owner, width, height
+                FROM images
+                WHERE id = $1
+            "#,
+			id
+		)
+		.fetch_optional(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to get image"))?
+		.ok_or(AppError::not_found("Image not found"))
+	}
+
+	pub fn metadata(&self) -> ImageMetadata {
+		let variants = ThumbnailVariant::all()
+			.map(|variant| {
+				let crop = match variant.is_square() {
+					true => "&crop=square",
+					false => "",
+				};
+				let url = format!(
+					"/image/thumbnail/{}?size={}{}",
+					self.id,
+					variant.size(),
+					crop
+				);
+				(variant.name(), url)
+			})
+			.collect();
+		ImageMetadata {
+			id: self.id,
+			width: self.width,
+			height: self.height,
+			url: format!("/image/{}", self.id),
+			variants,
+		}
+	}
+
+	/// Deletes the image from storage and the database. Recipes and steps that used it lose
+	/// their image; their ids are returned.
+	pub async fn delete(&self, pool: &PgPool, storage: &dyn ImageStorage) -> AppResult<Vec<Uuid>> {
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Failed to start transaction"))?;
+		// Locking the row makes recipes that start using the image meanwhile wait, and then fail
+		sqlx::query!(
+			r#"
+                SELECT id FROM images
+                WHERE id = $1
+                FOR UPDATE
+            "#,
+			self.id
+		)
+		.fetch_optional(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to delete image entry"))?;
+		let affected = sqlx::query_scalar!(
+			r#"
+                SELECT id AS "id!" FROM recipes WHERE image_id = $1
+                UNION
+                SELECT recipe_id FROM recipe_steps WHERE image_id = $1
+            "#,
+			self.id
+		)
+		.fetch_all(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to find recipes using image"))?;
+		sqlx::query!(
+			r#"
+                DELETE FROM images
+                WHERE id = $1
+            "#,
+			self.id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to delete image entry"))?;
+		// Dropping the transaction on failure keeps the row to try again with
+		storage.delete(&self.id, &self.delete_token).await?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		Ok(affected)
+	}
+
+	/// Returns which of the images still exist
+	pub async fn existing(pool: &PgPool, ids: &[Uuid]) -> AppResult<Vec<Uuid>> {
+		sqlx::query_scalar!(
+			r#"
+                SELECT id FROM images
+                WHERE id = ANY($1)
+            "#,
+			ids
+		)
+		.fetch_all(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to get images"))
+	}
+
+	/// Puts an image into storage and records it as owned by `owner`
+	pub async fn upload(
+		pool: &PgPool,
+		storage: &dyn ImageStorage,
+		image_bytes: &Bytes,
+		file_name: &str,
+		owner: &Uuid,
+	) -> AppResult<Self> {
+		let stored = storage.upload(image_bytes, file_name).await?;
+		Image::create(pool, &stored, owner).await
+	}
+
+	/// Finds images older than `grace_hours` that are not used by any recipe, step, past revision or avatar,
+	/// like uploads for drafts that were never saved
+	pub async fn find_orphans(pool: &PgPool, grace_hours: i32) -> AppResult<Vec<OrphanedImage>> {
+		sqlx::query_as!(
+			OrphanedImage,
+			r#"
+                SELECT i.id, i.delete_token, i.owner, i.created_at
+                FROM images i
+                WHERE i.created_at <= NOW() - make_interval(hours => $1)
+                    AND NOT EXISTS (SELECT 1 FROM recipes r WHERE r.image_id = i.id)
+                    AND NOT EXISTS (SELECT 1 FROM recipe_steps s WHERE s.image_id = i.id)
+                    AND NOT EXISTS (SELECT 1 FROM users u WHERE u.avatar_id = i.id)
+                    AND NOT EXISTS (
+                        SELECT 1 FROM recipe_revisions rr
+                        WHERE rr.snapshot @> jsonb_build_object('metadata', jsonb_build_object('imageId', i.id))
+                            OR rr.snapshot @> jsonb_build_object('steps', jsonb_build_array(jsonb_build_object('imageId', i.id)))
+                    )
+                ORDER BY i.created_at
+            "#,
+			grace_hours
+		)
+		.fetch_all(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to find orphaned images"))
+	}
+
+	/// Deletes orphaned images, or only lists them for a dry run
+	pub async fn collect_orphans(
+		pool: &PgPool,
+		storage: &dyn ImageStorage,
+		grace_hours: i32,
+		dry_run: bool,
+	) -> AppResult<OrphanCollection> {
+		let candidates = Self::find_orphans(pool, grace_hours).await?;
+		let mut deleted = 0;
+		let mut failed = 0;
+		if !dry_run {
+			for orphan in &candidates {
+				match Self::delete_if_orphaned(pool, storage, orphan, grace_hours).await {
+					Ok(true) => deleted += 1,
+					Ok(false) => {}
+					Err(e) => {
+						warn!("Failed to delete orphaned image {}: {}", orphan.id, e);
+						failed += 1;
+					}
+				}
+			}
+		}
+		Ok(OrphanCollection {
+			dry_run,
+			candidates,
+			deleted,
+			failed,
+		})
+	}
+
+	/// Deletes a candidate unless something started using it since it was found.
+	/// Returns whether it was deleted.
+	async fn delete_if_orphaned(
+		pool: &PgPool,
+		storage: &dyn ImageStorage,
+		orphan: &OrphanedImage,
+		grace_hours: i32,
+	) -> AppResult<bool> {
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Failed to start transaction"))?;
+		// With the row locked, the checks below see every recipe that saved the image meanwhile,
+		// and recipes that start using it now wait, and then fail
+		sqlx::query!(
+			r#"
+                SELECT id FROM images
+                WHERE id = $1
+                FOR UPDATE
+            "#,
+			orphan.id
+		)
+		.fetch_optional(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to delete image entry"))?;
+		let result = sqlx::query!(
+			r#"
+                DELETE FROM images i
+                WHERE i.id = $1
+                    AND i.created_at <= NOW() - make_interval(hours => $2)
+                    AND NOT EXISTS (SELECT 1 FROM recipes r WHERE r.image_id = i.id)
+                    AND NOT EXISTS (SELECT 1 FROM recipe_steps s WHERE s.image_id = i.id)
+                    AND NOT EXISTS (SELECT 1 FROM users u WHERE u.avatar_id = i.id)
+                    AND NOT EXISTS (
+                        SELECT 1 FROM recipe_revisions rr
+                        WHERE rr.snapshot @> jsonb_build_object('metadata', jsonb_build_object('imageId', i.id))
+                            OR rr.snapshot @> jsonb_build_object('steps', jsonb_build_array(jsonb_build_object('imageId', i.id)))
+                    )
+            "#,
+			orphan.id,
+			grace_hours
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to delete image entry"))?;
+		if result.rows_affected() == 0 {
+			return Ok(false);
+		}
+		// Dropping the transaction on failure keeps the row to try again with
+		storage.delete(&orphan.id, &orphan.delete_token).await?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		Ok(true)
+	}
 }
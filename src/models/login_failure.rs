@@ -0,0 +1,192 @@
+use chrono::naive::serde::ts_seconds;
+use serde::Serialize;
+use sqlx::{types::chrono::NaiveDateTime, PgPool};
+use uuid::Uuid;
+
+use crate::error::{AppError, AppResult};
+
+/// Failures are forgotten after this long without another one
+const FAILURE_MEMORY_HOURS: i32 = 24;
+/// The first lockout lasts this long, and every further failure doubles it
+const BASE_LOCKOUT_SECONDS: i64 = 30;
+const MAX_LOCKOUT_SECONDS: i64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy)]
+enum FailureKind {
+	Account,
+	Address,
+}
+
+impl FailureKind {
+	fn as_str(&self) -> &'static str {
+		match self {
+			FailureKind::Account => "account",
+			FailureKind::Address => "address",
+		}
+	}
+
+	/// Failures allowed before logins get locked. Addresses get more, since several people
+	/// can share one.
+	fn free_attempts(&self) -> i32 {
+		match self {
+			FailureKind::Account => 5,
+			FailureKind::Address => 20,
+		}
+	}
+}
+
+/// Emails are compared case-insensitively here, so changing the case does not get around a lockout
+fn account_key(email: &str) -> String {
+	email.trim().to_lowercase()
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Lockout {
+	/// Either `account` or `address`
+	pub kind: String,
+	/// The email or client address that is locked
+	pub key: String,
+	/// The locked account, if the email belongs to one
+	pub user_id: Option<Uuid>,
+	pub failures: i32,
+	#[serde(with = "ts_seconds")]
+	pub last_failure: NaiveDateTime,
+	#[serde(with = "ts_seconds")]
+	pub locked_until: NaiveDateTime,
+}
+
+/// Failed login tracking, which locks out accounts and client addresses for a while after
+/// too many failures
+pub struct LoginFailures;
+
+impl LoginFailures {
+	/// Fails if logins for the email or from the address are currently locked
+	pub async fn check(pool: &PgPool, email: &str, address: &str) -> AppResult<()> {
+		let retry_after = sqlx::query_scalar!(
+			r#"
+			SELECT CEIL(EXTRACT(EPOCH FROM MAX(locked_until) - NOW()))::BIGINT
+			FROM login_failures
+			WHERE ((kind = $1 AND key = $2) OR (kind = $3 AND key = $4)) AND locked_until > NOW()
+			"#,
+			FailureKind::Account.as_str(),
+			account_key(email),
+			FailureKind::Address.as_str(),
+			address
+		)
+		.fetch_one(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to check login attempts"))?;
+		match retry_after {
+			Some(seconds) => Err(AppError::too_many_requests(format!(
+				"Too many failed logins, please try again in {} seconds",
+				seconds.max(1)
+			))),
+			None => Ok(()),
+		}
+	}
+
+	pub async fn record(pool: &PgPool, email: &str, address: &str) -> AppResult<()> {
+		Self::record_one(pool, FailureKind::Account, &account_key(email)).await?;
+		Self::record_one(pool, FailureKind::Address, address).await
+	}
+
+	async fn record_one(pool: &PgPool, kind: FailureKind, key: &str) -> AppResult<()> {
+		let failures = sqlx::query_scalar!(
+			r#"
+			INSERT INTO login_failures (kind, key, failures)
+			VALUES ($1, $2, 1)
+			ON CONFLICT (kind, key) DO UPDATE
+			SET failures = CASE
+					WHEN login_failures.last_failure <= NOW() - make_interval(hours => $3) THEN 1
+					ELSE login_failures.failures + 1
+				END,
+				last_failure = NOW()
+			RETURNING failures
+			"#,
+			kind.as_str(),
+			key,
+			FAILURE_MEMORY_HOURS
+		)
+		.fetch_one(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to record login attempt"))?;
+		let excess = failures - kind.free_attempts();
+		if excess <= 0 {
+			return Ok(());
+		}
+		// Doubles with every failure past the free ones, up to a maximum the shift cannot overflow
+		let lockout = (BASE_LOCKOUT_SECONDS << (excess - 1).min(7)).min(MAX_LOCKOUT_SECONDS);
+		sqlx::query!(
+			r#"
+			UPDATE login_failures
+			SET locked_until = NOW() + make_interval(secs => $3)
+			WHERE kind = $1 AND key = $2
+			"#,
+			kind.as_str(),
+			key,
+			lockout as f64
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to record login attempt"))?;
+		Ok(())
+	}
+
+	/// Forgets the failures of an account after a successful login. Those of the address are
+	/// kept, so logging into one account does not allow guessing at others.
+	pub async fn clear(pool: &PgPool, email: &str) -> AppResult<()> {
+		Self::unlock(pool, FailureKind::Account.as_str(), &account_key(email)).await?;
+		Ok(())
+	}
+
+	/// Returns whether there was anything to unlock
+	pub async fn unlock(pool: &PgPool, kind: &str, key: &str) -> AppResult<bool> {
+		let result = sqlx::query!(
+			r#"
+			DELETE FROM login_failures
+			WHERE kind = $1 AND key = $2
+			"#,
+			kind,
+			key
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to clear login attempts"))?;
+		Ok(result.rows_affected() > 0)
+	}
+
+	pub async fn list_locked(pool: &PgPool) -> AppResult<Vec<Lockout>> {
+		sqlx::query_as!(
+			Lockout,
+			r#"
+			SELECT f.kind, f.key, u.id AS "user_id?", f.failures, f.last_failure,
+				f.locked_until AS "locked_until!"
+			FROM login_failures f
+			LEFT JOIN users u ON f.kind = $1 AND LOWER(u.email) = f.key
+			WHERE f.locked_until > NOW()
+			ORDER BY f.locked_until DESC
+			"#,
+			FailureKind::Account.as_str()
+		)
+		.fetch_all(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to get lockouts"))
+	}
+
+	/// Deletes failures that no longer count, returning how many there were
+	pub async fn purge_stale(pool: &PgPool) -> AppResult<u64> {
+		let result = sqlx::query!(
+			r#"
+			DELETE FROM login_failures
+			WHERE last_failure <= NOW() - make_interval(hours => $1)
+				AND (locked_until IS NULL OR locked_until <= NOW())
+			"#,
+			FAILURE_MEMORY_HOURS
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to purge login attempts"))?;
+		Ok(result.rows_affected())
+	}
+}
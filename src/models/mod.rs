@@ -1,4 +1,18 @@
 pub mod admin;
+pub mod api_token;
+pub mod archive;
+pub mod email_verification;
 pub mod image;
+pub mod login_failure;
+pub mod oidc;
+pub mod password_reset;
+pub mod profile;
 pub mod recipe;
+pub mod revision;
+pub mod schema_org;
+pub mod session;
+pub mod tag;
+pub mod two_factor;
+pub mod unit;
 pub mod user;
+pub mod verified_user;
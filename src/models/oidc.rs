@@ -0,0 +1,190 @@
+use rand::Rng;
+use sqlx::{PgConnection, PgPool};
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+	error::{AppError, AppResult},
+	external::oidc::IdTokenClaims,
+};
+
+use super::user::{generate_token, hash_password, token_digest, validate_username, User};
+
+const LOGIN_VALIDITY_MINUTES: i32 = 10;
+
+/// A login that has been sent to the identity provider
+pub struct OidcLogin {
+	pub code_verifier: String,
+	pub nonce: String,
+	pub link_user_id: Option<Uuid>,
+}
+
+impl OidcLogin {
+	/// Starts a login, or links an identity to `link_user` if given.
+	/// Returns the state to pass to the provider along with the login.
+	pub async fn create(pool: &PgPool, link_user: Option<&Uuid>) -> AppResult<(String, OidcLogin)> {
+		let state = generate_token();
+		let login = OidcLogin {
+			code_verifier: generate_token(),
+			nonce: generate_token(),
+			link_user_id: link_user.copied(),
+		};
+		sqlx::query!(
+			r#"
+			INSERT INTO oidc_logins (state_hash, code_verifier, nonce, link_user_id, expires_at)
+			VALUES ($1, $2, $3, $4, NOW() + make_interval(mins => $5))
+			"#,
+			token_digest(&state),
+			login.code_verifier,
+			login.nonce,
+			login.link_user_id,
+			LOGIN_VALIDITY_MINUTES
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to start login"))?;
+		Ok((state, login))
+	}
+
+	/// Finds the login a provider sent a user back from. Each login can only be completed once.
+	pub async fn take(pool: &PgPool, state: &str) -> AppResult<OidcLogin> {
+		let login = sqlx::query_as!(
+			OidcLogin,
+			r#"
+			DELETE FROM oidc_logins
+			WHERE state_hash = $1 AND expires_at > NOW()
+			RETURNING code_verifier, nonce, link_user_id
+			"#,
+			token_digest(state)
+		)
+		.fetch_optional(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to check login"))?;
+		sqlx::query!(
+			r#"
+			DELETE FROM oidc_logins
+			WHERE expires_at <= NOW()
+			"#
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to check login"))?;
+		login.ok_or(AppError::unauthorized("Login expired, please log in again"))
+	}
+}
+
+pub struct OidcIdentity;
+
+impl OidcIdentity {
+	pub async fn find_user(pool: &PgPool, claims: &IdTokenClaims) -> AppResult<Option<User>> {
+		let user_id = sqlx::query_scalar!(
+			r#"
+			SELECT user_id
+			FROM oidc_identities
+			WHERE provider = $1 AND subject = $2
+			"#,
+			claims.iss,
+			claims.sub
+		)
+		.fetch_optional(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to find identity"))?;
+		match user_id {
+			Some(id) => Ok(Some(User::from_uuid(pool, &id).await?)),
+			None => Ok(None),
+		}
+	}
+
+	pub async fn link(
+		conn: &mut PgConnection,
+		claims: &IdTokenClaims,
+		user_id: &Uuid,
+	) -> AppResult<()> {
+		sqlx::query!(
+			r#"
+			INSERT INTO oidc_identities (provider, subject, user_id)
+			VALUES ($1, $2, $3)
+			"#,
+			claims.iss,
+			claims.sub,
+			user_id
+		)
+		.execute(conn)
+		.await
+		.map_err(|e| match e {
+			sqlx::Error::Database(e) if e.is_unique_violation() => {
+				AppError::bad_request("This identity is already linked to an account")
+			}
+			_ => AppError::internal("Failed to link identity"),
+		})?;
+		info!("Linked identity {} to user {}", claims.sub, user_id);
+		Ok(())
+	}
+
+	/// Creates an account for a new identity as part of a larger transaction. Provisioned accounts
+	/// have no usable password, though one can be set through a password reset.
+	pub async fn provision(conn: &mut PgConnection, claims: &IdTokenClaims) -> AppResult<User> {
+		let email = claims
+			.email
+			.as_ref()
+			.filter(|_| claims.email_verified)
+			.ok_or(AppError::forbidden(
+				"The identity provider did not share a verified email address",
+			))?;
+
+		let base: String = claims
+			.preferred_username
+			.as_deref()
+			.unwrap_or(email.split('@').next().unwrap_or_default())
+			.chars()
+			.filter(|c| c.is_alphanumeric() || *c == '_' || *c == '-' || *c == '.')
+			.take(32)
+			.collect();
+		let base = match base.len() < 3 {
+			true => "user".to_string(),
+			false => base,
+		};
+		let suffixed = || format!("{}{}", base, rand::thread_rng().gen_range(1000..10000));
+		let mut username = match validate_username(&base) {
+			Ok(()) => base.clone(),
+			Err(_) => suffixed(),
+		};
+		let password = hash_password(&generate_token())?;
+		let mut attempts = 0;
+		// Usernames are unique, so pick another one if the preferred one is taken
+		let user = loop {
+			let user = sqlx::query_as!(
+				User,
+				r#"
+				INSERT INTO users (username, email, password, verified_at)
+				VALUES ($1, $2, $3, NOW())
+				ON CONFLICT (username) DO NOTHING
+				RETURNING id, username, email, password, is_admin, verified_at,
+					totp_enabled_at IS NOT NULL AS "two_factor_enabled!"
+				"#,
+				username,
+				email,
+				password
+			)
+			.fetch_optional(&mut *conn)
+			.await
+			.map_err(|e| match e {
+				sqlx::Error::Database(e) if e.is_unique_violation() => AppError::forbidden(
+					"An account with this email already exists, please log in and link it",
+				),
+				_ => AppError::internal("Failed to create user"),
+			})?;
+			match user {
+				Some(user) => break user,
+				None if attempts < 5 => {
+					attempts += 1;
+					username = suffixed();
+				}
+				None => return Err(AppError::internal("Failed to find a free username")),
+			}
+		};
+		Self::link(conn, claims, &user.id).await?;
+		info!("Provisioned user {} for identity {}", user.id, claims.sub);
+		Ok(user)
+	}
+}
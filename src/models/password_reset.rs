@@ -0,0 +1,95 @@
+use sqlx::PgPool;
+
+use crate::error::{AppError, AppResult};
+
+use super::user::{generate_token, token_digest, User};
+
+/// How long a reset link stays usable
+pub const RESET_VALIDITY_MINUTES: i32 = 60;
+/// Minimum time between two reset mails to the same user, so the form cannot be used to flood inboxes
+const RESET_COOLDOWN_MINUTES: i32 = 5;
+
+pub struct PasswordReset;
+
+impl PasswordReset {
+	/// Issues a new reset token for the user, replacing any unused ones, unless they have asked
+	/// for one lately. Returns the token itself, which is only ever stored as a digest.
+	pub async fn create(pool: &PgPool, user: &User) -> AppResult<String> {
+		let cooling_down = sqlx::query_scalar!(
+			r#"
+			SELECT EXISTS (
+				SELECT 1 FROM password_resets
+				WHERE user_id = $1 AND created_at > NOW() - make_interval(mins => $2)
+			) AS "cooling_down!"
+			"#,
+			user.id,
+			RESET_COOLDOWN_MINUTES
+		)
+		.fetch_one(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to check reset tokens"))?;
+		if cooling_down {
+			return Err(AppError::too_many_requests(
+				"Too many reset mails, please try again later",
+			));
+		}
+		let token = generate_token();
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Failed to start transaction"))?;
+		sqlx::query!(
+			r#"
+			DELETE FROM password_resets
+			WHERE user_id = $1 AND used_at IS NULL
+			"#,
+			user.id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to issue reset token"))?;
+		sqlx::query!(
+			r#"
+			INSERT INTO password_resets (token_hash, user_id, expires_at)
+			VALUES ($1, $2, NOW() + make_interval(mins => $3))
+			"#,
+			token_digest(&token),
+			user.id,
+			RESET_VALIDITY_MINUTES
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to issue reset token"))?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		Ok(token)
+	}
+
+	/// Sets a new password using a reset token. Each token works only once.
+	pub async fn redeem(pool: &PgPool, token: &str, password: &str) -> AppResult<User> {
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Failed to start transaction"))?;
+		let user_id = sqlx::query_scalar!(
+			r#"
+			UPDATE password_resets
+			SET used_at = NOW()
+			WHERE token_hash = $1 AND used_at IS NULL AND expires_at > NOW()
+			RETURNING user_id
+			"#,
+			token_digest(token)
+		)
+		.fetch_optional(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to check reset token"))?
+		.ok_or(AppError::bad_request("Invalid or expired reset token"))?;
+		let user = User::from_uuid(pool, &user_id).await?;
+		user.set_password(&mut tx, password).await?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		Ok(user)
+	}
+}
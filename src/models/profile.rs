@@ -0,0 +1,68 @@
+use chrono::naive::serde::ts_seconds;
+use serde::Serialize;
+use sqlx::{types::chrono::NaiveDateTime, PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::error::{AppError, AppResult};
+
+/// What anyone can see about a user. Never contains the email address.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PublicProfile {
+	#[serde(skip)]
+	pub id: Uuid,
+	pub username: String,
+	pub display_name: Option<String>,
+	pub bio: Option<String>,
+	pub avatar_id: Option<Uuid>,
+	#[serde(with = "ts_seconds")]
+	pub joined_at: NaiveDateTime,
+	pub recipe_count: i64,
+}
+
+impl PublicProfile {
+	pub async fn from_username(pool: &PgPool, username: &str) -> AppResult<Self> {
+		sqlx::query_as!(
+			PublicProfile,
+			r#"
+			SELECT id, username, display_name, bio, avatar_id, created_at AS joined_at,
+				(SELECT COUNT(*) FROM recipes WHERE author = users.id) AS "recipe_count!"
+			FROM users
+			WHERE username = $1
+			"#,
+			username
+		)
+		.fetch_optional(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to get user"))?
+		.ok_or(AppError::not_found("User not found"))
+	}
+
+	/// Updates the profile fields of a user. `None` leaves a field as it is, an empty value clears it.
+	pub async fn update(
+		conn: &mut PgConnection,
+		user_id: &Uuid,
+		display_name: Option<&str>,
+		bio: Option<&str>,
+		avatar_id: Option<Option<Uuid>>,
+	) -> AppResult<()> {
+		sqlx::query!(
+			r#"
+			UPDATE users
+			SET display_name = CASE WHEN $2::TEXT IS NULL THEN display_name ELSE NULLIF($2, '') END,
+				bio = CASE WHEN $3::TEXT IS NULL THEN bio ELSE NULLIF($3, '') END,
+				avatar_id = CASE WHEN $4 THEN $5 ELSE avatar_id END
+			WHERE id = $1
+			"#,
+			user_id,
+			display_name,
+			bio,
+			avatar_id.is_some(),
+			avatar_id.flatten()
+		)
+		.execute(conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to update profile"))?;
+		Ok(())
+	}
+}
@@ -1,14 +1,22 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
 use chrono::naive::serde::ts_seconds;
 use serde::{Deserialize, Serialize};
 use sqlx::{
 	types::{chrono::NaiveDateTime, BigDecimal},
-	PgPool,
+	PgConnection, PgPool,
 };
 use uuid::Uuid;
 
 use crate::error::{AppError, AppResult};
 
-#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+use super::{
+	revision::RecipeRevision,
+	tag::Tag,
+	unit::{self, UnitKind, UnitSystem},
+	user::User,
+};
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RecipeMetadata {
 	pub id: Uuid,
@@ -19,10 +27,13 @@ pub struct RecipeMetadata {
 	pub time_estimate_active: Option<BigDecimal>,
 	pub time_estimate_total: Option<BigDecimal>,
 	pub source_url: Option<String>,
+	pub servings: Option<i32>,
 	#[serde(with = "ts_seconds")]
 	pub created_at: NaiveDateTime,
 	#[serde(with = "ts_seconds")]
 	pub edited_at: NaiveDateTime,
+	#[serde(default)]
+	pub tags: Vec<String>,
 }
 
 #[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
@@ -33,6 +44,22 @@ pub struct RecipeIngredient {
 	pub name: String,
 }
 
+impl RecipeIngredient {
+	/// Returns this ingredient scaled by `numerator / denominator`, rounded to a
+	/// precision that makes sense for its unit
+	pub fn scaled(&self, numerator: i32, denominator: i32) -> RecipeIngredient {
+		if unit::lookup(&self.unit).is_some_and(|u| u.kind == UnitKind::Temperature) {
+			return self.clone();
+		}
+		let exact = &self.quantity * BigDecimal::from(numerator) / BigDecimal::from(denominator);
+		RecipeIngredient {
+			quantity: unit::round(&exact, &self.unit),
+			unit: self.unit.clone(),
+			name: self.name.clone(),
+		}
+	}
+}
+
 #[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RecipeStep {
@@ -40,7 +67,7 @@ pub struct RecipeStep {
 	pub image_id: Option<Uuid>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Recipe {
 	pub metadata: RecipeMetadata,
@@ -48,7 +75,7 @@ pub struct Recipe {
 	pub steps: Vec<RecipeStep>,
 }
 
-#[derive(Debug, Clone, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct RecipeCreation {
 	pub name: String,
@@ -57,11 +84,101 @@ pub struct RecipeCreation {
 	pub time_estimate_active: Option<BigDecimal>,
 	pub time_estimate_total: Option<BigDecimal>,
 	pub source_url: Option<String>,
+	pub servings: Option<i32>,
 	pub ingredients: Vec<RecipeIngredient>,
 	pub steps: Vec<RecipeStep>,
+	#[serde(default)]
+	pub tags: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecipeListFilter {
+	/// Only list recipes with these tags. Empty means no filtering.
+	pub tags: Vec<String>,
+	/// Whether a recipe needs every tag in `tags`, or just one of them
+	pub match_all_tags: bool,
+	/// Only list recipes by this user
+	pub author: Option<Uuid>,
 }
 
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipeSearchResult {
+	#[serde(flatten)]
+	pub metadata: RecipeMetadata,
+	pub rank: f32,
+	pub snippet: String,
+}
+
+/// Relative weight of matches in each part of a recipe when ranking search results
 #[derive(Clone, Copy)]
+pub struct RecipeSearchWeights {
+	pub title: f32,
+	pub ingredients: f32,
+	pub description: f32,
+	pub steps: f32,
+}
+
+impl Default for RecipeSearchWeights {
+	fn default() -> Self {
+		RecipeSearchWeights {
+			title: 1.0,
+			ingredients: 0.6,
+			description: 0.4,
+			steps: 0.2,
+		}
+	}
+}
+
+impl Recipe {
+	/// Scales all ingredients to make the given number of servings instead
+	pub fn scale_to(&mut self, servings: i32) -> AppResult<()> {
+		let original = self
+			.metadata
+			.servings
+			.ok_or(AppError::bad_request("Recipe does not specify servings"))?;
+		self.ingredients = self
+			.ingredients
+			.iter()
+			.map(|i| i.scaled(servings, original))
+			.collect();
+		self.metadata.servings = Some(servings);
+		Ok(())
+	}
+
+	/// Converts all ingredients with known units into the given unit system
+	pub fn convert_units(&mut self, system: UnitSystem) {
+		for ingredient in self.ingredients.iter_mut() {
+			let (quantity, unit) = unit::convert(
+				&ingredient.quantity,
+				&ingredient.unit,
+				&ingredient.name,
+				system,
+			);
+			ingredient.quantity = quantity;
+			ingredient.unit = unit;
+		}
+	}
+}
+
+impl From<Recipe> for RecipeCreation {
+	fn from(recipe: Recipe) -> Self {
+		RecipeCreation {
+			name: recipe.metadata.title,
+			description: recipe.metadata.description,
+			image_id: recipe.metadata.image_id,
+			time_estimate_active: recipe.metadata.time_estimate_active,
+			time_estimate_total: recipe.metadata.time_estimate_total,
+			source_url: recipe.metadata.source_url,
+			servings: recipe.metadata.servings,
+			ingredients: recipe.ingredients,
+			steps: recipe.steps,
+			tags: recipe.metadata.tags,
+		}
+	}
+}
+
+#[derive(Clone, Copy, PartialEq)]
 pub enum RecipeListSort {
 	DateAscending = 1,
 	DateDescending = 2,
@@ -69,18 +186,95 @@ pub enum RecipeListSort {
 	NameDescending = 4,
 }
 
+impl RecipeListSort {
+	fn reversed(self) -> Self {
+		match self {
+			RecipeListSort::DateAscending => RecipeListSort::DateDescending,
+			RecipeListSort::DateDescending => RecipeListSort::DateAscending,
+			RecipeListSort::NameAscending => RecipeListSort::NameDescending,
+			RecipeListSort::NameDescending => RecipeListSort::NameAscending,
+		}
+	}
+}
+
+/// Position in a recipe listing, pointing just past `id` in the given direction.
+/// Handed to clients as an opaque string.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecipeCursor {
+	#[serde(rename = "o")]
+	ordering: i64,
+	#[serde(rename = "c")]
+	created_at: Option<NaiveDateTime>,
+	#[serde(rename = "t")]
+	title: Option<String>,
+	#[serde(rename = "i")]
+	id: Uuid,
+	#[serde(rename = "b")]
+	backward: bool,
+}
+
+impl RecipeCursor {
+	fn new(ordering: RecipeListSort, recipe: &RecipeMetadata, backward: bool) -> Self {
+		let by_date = matches!(
+			ordering,
+			RecipeListSort::DateAscending | RecipeListSort::DateDescending
+		);
+		RecipeCursor {
+			ordering: ordering as i64,
+			created_at: by_date.then_some(recipe.created_at),
+			title: (!by_date).then(|| recipe.title.clone()),
+			id: recipe.id,
+			backward,
+		}
+	}
+
+	pub fn encode(&self) -> String {
+		URL_SAFE_NO_PAD.encode(serde_json::to_vec(self).unwrap_or_default())
+	}
+
+	pub fn decode(cursor: &str) -> AppResult<Self> {
+		URL_SAFE_NO_PAD
+			.decode(cursor)
+			.ok()
+			.and_then(|bytes| serde_json::from_slice(&bytes).ok())
+			.ok_or(AppError::bad_request("Invalid cursor"))
+	}
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipePage {
+	pub recipes: Vec<RecipeMetadata>,
+	pub next: Option<String>,
+	pub prev: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub total: Option<i64>,
+}
+
 impl Recipe {
 	pub async fn create(pool: &PgPool, author: &Uuid, data: &RecipeCreation) -> AppResult<Recipe> {
 		let mut tx = pool
 			.begin()
 			.await
 			.map_err(|_| AppError::internal("Internal db error"))?;
+		let recipe = Recipe::insert(&mut tx, author, data).await?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Internal db error"))?;
+		Ok(recipe)
+	}
 
+	/// Creates a recipe as part of a larger transaction
+	pub async fn insert(
+		conn: &mut PgConnection,
+		author: &Uuid,
+		data: &RecipeCreation,
+	) -> AppResult<Recipe> {
 		let id = Uuid::new_v4();
 		sqlx::query!(
 			r#"
-			INSERT INTO recipes (id, title, description, author, image_id, source_url, time_estimate_active, time_estimate_total)
-			VALUES ($1, $2, $3, $4, $5, $6, $7, $8)
+			INSERT INTO recipes (id, title, description, author, image_id, source_url, time_estimate_active, time_estimate_total, servings)
+			VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
 			"#,
 			id,
 			data.name,
@@ -90,11 +284,110 @@ impl Recipe {
             data.source_url,
 			data.time_estimate_active,
 			data.time_estimate_total,
+			data.servings,
 		)
-		.execute(&mut *tx)
+		.execute(&mut *conn)
 		.await
 		.map_err(|_| AppError::internal("Error creating recipe"))?;
 
+		Recipe::insert_contents(&mut *conn, &id, data).await?;
+		Tag::set_for_recipe(&mut *conn, &id, &data.tags).await?;
+		Recipe::refresh_search_document(&mut *conn, &id).await?;
+		let recipe = Recipe::fetch(&mut *conn, &id).await?;
+		RecipeRevision::record(&mut *conn, &recipe, author).await?;
+		Ok(recipe)
+	}
+
+	/// Replaces the contents of a recipe, which only its author or an admin may do
+	pub async fn update(
+		pool: &PgPool,
+		id: &Uuid,
+		editor: &User,
+		data: &RecipeCreation,
+	) -> AppResult<Recipe> {
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Internal db error"))?;
+
+		// Locked, so the author cannot change before the edit is saved
+		let author = sqlx::query_scalar!(
+			r#"
+			SELECT author FROM recipes
+			WHERE id = $1
+			FOR UPDATE
+			"#,
+			id
+		)
+		.fetch_optional(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Error updating recipe"))?
+		.ok_or(AppError::not_found("Recipe not found"))?;
+		if author != editor.id && !editor.is_admin {
+			return Err(AppError::forbidden("Only the author can edit this recipe"));
+		}
+
+		sqlx::query!(
+			r#"
+			UPDATE recipes
+			SET title = $2, description = $3, image_id = $4, source_url = $5,
+				time_estimate_active = $6, time_estimate_total = $7, servings = $8, edited_at = NOW()
+			WHERE id = $1
+			"#,
+			id,
+			data.name,
+			data.description,
+			data.image_id,
+			data.source_url,
+			data.time_estimate_active,
+			data.time_estimate_total,
+			data.servings,
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Error updating recipe"))?;
+
+		sqlx::query!(
+			r#"
+			DELETE FROM recipe_ingredients
+			WHERE recipe_id = $1
+			"#,
+			id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Error updating recipe"))?;
+
+		sqlx::query!(
+			r#"
+			DELETE FROM recipe_steps
+			WHERE recipe_id = $1
+			"#,
+			id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Error updating recipe"))?;
+
+		Recipe::insert_contents(&mut tx, id, data).await?;
+		Tag::set_for_recipe(&mut tx, id, &data.tags).await?;
+		Recipe::refresh_search_document(&mut tx, id).await?;
+		let recipe = Recipe::fetch(&mut tx, id).await?;
+		RecipeRevision::record(&mut tx, &recipe, &editor.id).await?;
+
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Internal db error"))?;
+
+		Ok(recipe)
+	}
+
+	/// Inserts the ingredients and steps of a recipe that has none
+	async fn insert_contents(
+		conn: &mut PgConnection,
+		id: &Uuid,
+		data: &RecipeCreation,
+	) -> AppResult<()> {
 		for (num, ingredient) in data.ingredients.iter().enumerate() {
 			sqlx::query!(
 				r#"
@@ -104,12 +397,12 @@ impl Recipe {
 				id,
 				num as i32,
 				ingredient.quantity,
-				ingredient.unit,
+				unit::normalize(&ingredient.unit),
 				ingredient.name
 			)
-			.execute(&mut *tx)
+			.execute(&mut *conn)
 			.await
-			.map_err(|_| AppError::internal("Error creating recipe"))?;
+			.map_err(|_| AppError::internal("Error saving recipe"))?;
 		}
 
 		for (num, step) in data.steps.iter().enumerate() {
@@ -123,31 +416,56 @@ impl Recipe {
 				step.description,
 				step.image_id,
 			)
-			.execute(&mut *tx)
+			.execute(&mut *conn)
 			.await
-			.map_err(|_| AppError::internal("Error creating recipe"))?;
+			.map_err(|_| AppError::internal("Error saving recipe"))?;
 		}
+		Ok(())
+	}
 
-		tx.commit()
+	pub async fn from_uuid(pool: &PgPool, id: &Uuid) -> AppResult<Recipe> {
+		let mut conn = pool
+			.acquire()
 			.await
 			.map_err(|_| AppError::internal("Internal db error"))?;
+		Recipe::fetch(&mut conn, id).await
+	}
 
-		Recipe::from_uuid(pool, &id).await
+	/// Like `from_uuid`, but a missing recipe is not an error
+	pub async fn find(pool: &PgPool, id: &Uuid) -> AppResult<Option<Recipe>> {
+		let mut conn = pool
+			.acquire()
+			.await
+			.map_err(|_| AppError::internal("Internal db error"))?;
+		Recipe::fetch_optional(&mut conn, id).await
 	}
 
-	pub async fn from_uuid(pool: &PgPool, id: &Uuid) -> AppResult<Recipe> {
+	async fn fetch(conn: &mut PgConnection, id: &Uuid) -> AppResult<Recipe> {
+		Recipe::fetch_optional(conn, id)
+			.await?
+			.ok_or(AppError::not_found("Recipe not found"))
+	}
+
+	async fn fetch_optional(conn: &mut PgConnection, id: &Uuid) -> AppResult<Option<Recipe>> {
 		let metadata = sqlx::query_as!(
 			RecipeMetadata,
 			r#"
-			SELECT id, title, description, author, image_id, time_estimate_active, time_estimate_total, source_url, created_at, edited_at
+			SELECT id, title, description, author, image_id, time_estimate_active, time_estimate_total, source_url, servings, created_at, edited_at,
+			ARRAY(
+				SELECT t.name FROM recipe_tags rt INNER JOIN tags t ON t.id = rt.tag_id
+				WHERE rt.recipe_id = recipes.id ORDER BY t.name
+			) AS "tags!"
 			FROM recipes
 			WHERE id = $1
 			"#,
 			id
 		)
-		.fetch_one(pool)
+		.fetch_optional(&mut *conn)
 		.await
-		.map_err(|_| AppError::not_found("Recipe not found"))?;
+		.map_err(|_| AppError::internal("Error getting recipe"))?;
+		let Some(metadata) = metadata else {
+			return Ok(None);
+		};
 
 		let ingredients = sqlx::query_as!(
 			RecipeIngredient,
@@ -159,9 +477,9 @@ impl Recipe {
 			"#,
 			id
 		)
-		.fetch_all(pool)
+		.fetch_all(&mut *conn)
 		.await
-		.map_err(|_| AppError::not_found("Recipe not found"))?;
+		.map_err(|_| AppError::internal("Error getting recipe"))?;
 
 		let steps = sqlx::query_as!(
 			RecipeStep,
@@ -173,41 +491,219 @@ impl Recipe {
 			"#,
 			id
 		)
-		.fetch_all(pool)
+		.fetch_all(&mut *conn)
 		.await
-		.map_err(|_| AppError::not_found("Recipe not found"))?;
+		.map_err(|_| AppError::internal("Error getting recipe"))?;
 
-		Ok(Recipe {
+		Ok(Some(Recipe {
 			metadata,
 			steps,
 			ingredients,
-		})
+		}))
 	}
 
 	pub async fn list_brief(
 		pool: &PgPool,
 		max_count: u64,
 		ordering: RecipeListSort,
-	) -> AppResult<Vec<RecipeMetadata>> {
-		let recipes = sqlx::query_as!(
+		cursor: Option<&RecipeCursor>,
+		filter: &RecipeListFilter,
+	) -> AppResult<RecipePage> {
+		if cursor.is_some_and(|c| c.ordering != ordering as i64) {
+			return Err(AppError::bad_request("Cursor does not match sort order"));
+		}
+		let backward = cursor.is_some_and(|c| c.backward);
+		// Walking backwards is the same as walking forwards in the opposite order,
+		// with the results flipped afterwards
+		let effective_ordering = match backward {
+			true => ordering.reversed(),
+			false => ordering,
+		};
+		// Fetch one extra row to find out whether there is another page
+		let mut recipes = sqlx::query_as!(
 			RecipeMetadata,
 			r#"
-			SELECT id, title, description, author, image_id, time_estimate_active, time_estimate_total, source_url, created_at, edited_at
+			SELECT id, title, description, author, image_id, time_estimate_active, time_estimate_total, source_url, servings, created_at, edited_at,
+			ARRAY(
+				SELECT t.name FROM recipe_tags rt INNER JOIN tags t ON t.id = rt.tag_id
+				WHERE rt.recipe_id = recipes.id ORDER BY t.name
+			) AS "tags!"
 			FROM recipes
+			WHERE (
+				$3::UUID IS NULL
+				OR ($2 = 1 AND (created_at, id) > ($4::TIMESTAMP, $3))
+				OR ($2 = 2 AND (created_at, id) < ($4::TIMESTAMP, $3))
+				OR ($2 = 3 AND (title, id) > ($5::TEXT, $3))
+				OR ($2 = 4 AND (title, id) < ($5::TEXT, $3))
+			) AND recipe_matches_tags(id, $6, $7)
+				AND ($8::UUID IS NULL OR author = $8)
 			ORDER BY
 				CASE WHEN $2 = 1 THEN created_at END ASC,
 				CASE WHEN $2 = 2 THEN created_at END DESC,
 				CASE WHEN $2 = 3 THEN title END ASC,
-				CASE WHEN $2 = 4 THEN title END DESC
+				CASE WHEN $2 = 4 THEN title END DESC,
+				CASE WHEN $2 IN (1, 3) THEN id END ASC,
+				CASE WHEN $2 IN (2, 4) THEN id END DESC
 			LIMIT $1
 			"#,
-			max_count as i64,
-			ordering as i64,
+			max_count as i64 + 1,
+			effective_ordering as i64,
+			cursor.map(|c| c.id),
+			cursor.and_then(|c| c.created_at),
+			cursor.and_then(|c| c.title.clone()),
+			&filter.tags,
+			filter.match_all_tags,
+			filter.author,
 		)
 		.fetch_all(pool)
 		.await
 		.map_err(|_| AppError::internal("Error fetching recipes"))?;
-		Ok(recipes)
+
+		let has_more = recipes.len() as u64 > max_count;
+		recipes.truncate(max_count as usize);
+		if backward {
+			recipes.reverse();
+		}
+		let (has_next, has_prev) = match backward {
+			true => (true, has_more),
+			false => (has_more, cursor.is_some()),
+		};
+		let next = recipes
+			.last()
+			.filter(|_| has_next)
+			.map(|r| RecipeCursor::new(ordering, r, false).encode());
+		let prev = recipes
+			.first()
+			.filter(|_| has_prev)
+			.map(|r| RecipeCursor::new(ordering, r, true).encode());
+		Ok(RecipePage {
+			recipes,
+			next,
+			prev,
+			total: None,
+		})
+	}
+
+	/// Lists the ids of all recipes by `author`, or of every recipe if no author is given
+	pub async fn list_ids(pool: &PgPool, author: Option<&Uuid>) -> AppResult<Vec<Uuid>> {
+		sqlx::query_scalar!(
+			r#"
+			SELECT id
+			FROM recipes
+			WHERE $1::UUID IS NULL OR author = $1
+			ORDER BY created_at, id
+			"#,
+			author,
+		)
+		.fetch_all(pool)
+		.await
+		.map_err(|_| AppError::internal("Error listing recipes"))
+	}
+
+	pub async fn count(pool: &PgPool, filter: &RecipeListFilter) -> AppResult<i64> {
+		sqlx::query_scalar!(
+			r#"
+			SELECT COUNT(*) AS "count!"
+			FROM recipes
+			WHERE recipe_matches_tags(id, $1, $2)
+				AND ($3::UUID IS NULL OR author = $3)
+			"#,
+			&filter.tags,
+			filter.match_all_tags,
+			filter.author,
+		)
+		.fetch_one(pool)
+		.await
+		.map_err(|_| AppError::internal("Error counting recipes"))
+	}
+
+	pub async fn search(
+		pool: &PgPool,
+		query: &str,
+		max_count: u64,
+		weights: RecipeSearchWeights,
+	) -> AppResult<Vec<RecipeSearchResult>> {
+		// ts_rank expects the weights in D, C, B, A order
+		let rank_weights = vec![
+			weights.steps,
+			weights.description,
+			weights.ingredients,
+			weights.title,
+		];
+		let rows = sqlx::query!(
+			r#"
+			WITH matches AS (
+				SELECT r.*, ts_rank($2::REAL[], r.search_document, q) AS rank, q
+				FROM recipes r, websearch_to_tsquery('english', $1) q
+				WHERE r.search_document @@ q
+				ORDER BY rank DESC, r.created_at DESC
+				LIMIT $3
+			)
+			SELECT
+				m.id, m.title, m.description, m.author, m.image_id, m.time_estimate_active,
+				m.time_estimate_total, m.source_url, m.servings, m.created_at, m.edited_at,
+				m.rank AS "rank!",
+				ARRAY(
+					SELECT t.name FROM recipe_tags rt INNER JOIN tags t ON t.id = rt.tag_id
+					WHERE rt.recipe_id = m.id ORDER BY t.name
+				) AS "tags!",
+				ts_headline(
+					'english',
+					concat_ws(
+						' ... ',
+						m.description,
+						(SELECT string_agg(i.name, ', ' ORDER BY i.num) FROM recipe_ingredients i WHERE i.recipe_id = m.id),
+						(SELECT string_agg(s.description, ' ' ORDER BY s.num) FROM recipe_steps s WHERE s.recipe_id = m.id)
+					),
+					m.q,
+					'MaxFragments=2, MaxWords=20, MinWords=5'
+				) AS "snippet!"
+			FROM matches m
+			ORDER BY m.rank DESC, m.created_at DESC
+			"#,
+			query,
+			&rank_weights,
+			max_count as i64,
+		)
+		.fetch_all(pool)
+		.await
+		.map_err(|_| AppError::internal("Error searching recipes"))?;
+		Ok(rows
+			.into_iter()
+			.map(|row| RecipeSearchResult {
+				metadata: RecipeMetadata {
+					id: row.id,
+					title: row.title,
+					description: row.description,
+					author: row.author,
+					image_id: row.image_id,
+					time_estimate_active: row.time_estimate_active,
+					time_estimate_total: row.time_estimate_total,
+					source_url: row.source_url,
+					servings: row.servings,
+					created_at: row.created_at,
+					edited_at: row.edited_at,
+					tags: row.tags,
+				},
+				rank: row.rank,
+				snippet: row.snippet,
+			})
+			.collect())
+	}
+
+	async fn refresh_search_document(conn: &mut PgConnection, id: &Uuid) -> AppResult<()> {
+		sqlx::query!(
+			r#"
+			UPDATE recipes
+			SET search_document = recipe_search_document(id)
+			WHERE id = $1
+			"#,
+			id
+		)
+		.execute(conn)
+		.await
+		.map_err(|_| AppError::internal("Error indexing recipe"))?;
+		Ok(())
 	}
 
 	pub async fn delete(&self, pool: &PgPool) -> AppResult<()> {
@@ -0,0 +1,246 @@
+use chrono::naive::serde::ts_seconds;
+use serde::Serialize;
+use sqlx::{
+	types::{chrono::NaiveDateTime, BigDecimal, Json},
+	PgConnection, PgPool,
+};
+use uuid::Uuid;
+
+use crate::error::{AppError, AppResult};
+
+use super::recipe::{Recipe, RecipeIngredient};
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipeRevisionBrief {
+	pub num: i32,
+	pub editor: Option<Uuid>,
+	#[serde(with = "ts_seconds")]
+	pub created_at: NaiveDateTime,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipeRevision {
+	pub num: i32,
+	pub editor: Option<Uuid>,
+	#[serde(with = "ts_seconds")]
+	pub created_at: NaiveDateTime,
+	pub recipe: Recipe,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FieldChange {
+	pub field: &'static str,
+	pub old: Option<String>,
+	pub new: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct IngredientChange {
+	pub name: String,
+	pub old_quantity: BigDecimal,
+	pub new_quantity: BigDecimal,
+	pub old_unit: String,
+	pub new_unit: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StepChange {
+	pub num: usize,
+	pub old: Option<String>,
+	pub new: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipeDiff {
+	pub from: i32,
+	pub to: i32,
+	pub fields: Vec<FieldChange>,
+	pub ingredients_added: Vec<RecipeIngredient>,
+	pub ingredients_removed: Vec<RecipeIngredient>,
+	pub ingredients_changed: Vec<IngredientChange>,
+	pub steps: Vec<StepChange>,
+}
+
+impl RecipeRevision {
+	pub async fn record(conn: &mut PgConnection, recipe: &Recipe, editor: &Uuid) -> AppResult<()> {
+		// The recipe row is locked by the caller's transaction, so concurrent
+		// edits of the same recipe cannot pick the same revision number
+		sqlx::query!(
+			r#"
+			INSERT INTO recipe_revisions (recipe_id, num, editor, snapshot)
+			SELECT $1, COALESCE(MAX(num), 0) + 1, $2, $3
+			FROM recipe_revisions
+			WHERE recipe_id = $1
+			"#,
+			recipe.metadata.id,
+			editor,
+			Json(recipe) as _,
+		)
+		.execute(conn)
+		.await
+		.map_err(|_| AppError::internal("Error recording revision"))?;
+		Ok(())
+	}
+
+	pub async fn list(pool: &PgPool, recipe_id: &Uuid) -> AppResult<Vec<RecipeRevisionBrief>> {
+		sqlx::query_as!(
+			RecipeRevisionBrief,
+			r#"
+			SELECT num, editor, created_at
+			FROM recipe_revisions
+			WHERE recipe_id = $1
+			ORDER BY num
+			"#,
+			recipe_id
+		)
+		.fetch_all(pool)
+		.await
+		.map_err(|_| AppError::internal("Error fetching revisions"))
+	}
+
+	pub async fn from_num(pool: &PgPool, recipe_id: &Uuid, num: i32) -> AppResult<Self> {
+		let row = sqlx::query!(
+			r#"
+			SELECT num, editor, created_at, snapshot as "snapshot: Json<Recipe>"
+			FROM recipe_revisions
+			WHERE recipe_id = $1 AND num = $2
+			"#,
+			recipe_id,
+			num
+		)
+		.fetch_one(pool)
+		.await
+		.map_err(|_| AppError::not_found("Revision not found"))?;
+		Ok(Self {
+			num: row.num,
+			editor: row.editor,
+			created_at: row.created_at,
+			recipe: row.snapshot.0,
+		})
+	}
+
+	pub fn diff(&self, other: &RecipeRevision) -> RecipeDiff {
+		let old = &self.recipe;
+		let new = &other.recipe;
+
+		let mut fields = Vec::new();
+		let mut compare = |field: &'static str, a: Option<String>, b: Option<String>| {
+			if a != b {
+				fields.push(FieldChange {
+					field,
+					old: a,
+					new: b,
+				});
+			}
+		};
+		compare(
+			"title",
+			Some(old.metadata.title.clone()),
+			Some(new.metadata.title.clone()),
+		);
+		compare(
+			"description",
+			Some(old.metadata.description.clone()),
+			Some(new.metadata.description.clone()),
+		);
+		compare(
+			"imageId",
+			old.metadata.image_id.map(|i| i.to_string()),
+			new.metadata.image_id.map(|i| i.to_string()),
+		);
+		compare(
+			"timeEstimateActive",
+			old.metadata
+				.time_estimate_active
+				.as_ref()
+				.map(|t| t.to_string()),
+			new.metadata
+				.time_estimate_active
+				.as_ref()
+				.map(|t| t.to_string()),
+		);
+		compare(
+			"timeEstimateTotal",
+			old.metadata
+				.time_estimate_total
+				.as_ref()
+				.map(|t| t.to_string()),
+			new.metadata
+				.time_estimate_total
+				.as_ref()
+				.map(|t| t.to_string()),
+		);
+		compare(
+			"servings",
+			old.metadata.servings.map(|s| s.to_string()),
+			new.metadata.servings.map(|s| s.to_string()),
+		);
+		compare(
+			"tags",
+			Some(old.metadata.tags.join(", ")),
+			Some(new.metadata.tags.join(", ")),
+		);
+		compare(
+			"sourceUrl",
+			old.metadata.source_url.clone(),
+			new.metadata.source_url.clone(),
+		);
+
+		// Ingredients are matched by name, since their position carries no meaning
+		let key = |i: &RecipeIngredient| i.name.trim().to_lowercase();
+		let mut ingredients_removed = Vec::new();
+		let mut ingredients_changed = Vec::new();
+		for before in &old.ingredients {
+			match new.ingredients.iter().find(|i| key(i) == key(before)) {
+				None => ingredients_removed.push(before.clone()),
+				Some(after) => {
+					if before.quantity != after.quantity || before.unit != after.unit {
+						ingredients_changed.push(IngredientChange {
+							name: after.name.clone(),
+							old_quantity: before.quantity.clone(),
+							new_quantity: after.quantity.clone(),
+							old_unit: before.unit.clone(),
+							new_unit: after.unit.clone(),
+						});
+					}
+				}
+			}
+		}
+		let ingredients_added = new
+			.ingredients
+			.iter()
+			.filter(|after| !old.ingredients.iter().any(|i| key(i) == key(after)))
+			.cloned()
+			.collect();
+
+		// Steps are compared by position
+		let step_count = old.steps.len().max(new.steps.len());
+		let steps = (0..step_count)
+			.filter_map(|num| {
+				let a = old.steps.get(num).map(|s| s.description.clone());
+				let b = new.steps.get(num).map(|s| s.description.clone());
+				(a != b).then_some(StepChange {
+					num,
+					old: a,
+					new: b,
+				})
+			})
+			.collect();
+
+		RecipeDiff {
+			from: self.num,
+			to: other.num,
+			fields,
+			ingredients_added,
+			ingredients_removed,
+			ingredients_changed,
+			steps,
+		}
+	}
+}
@@ -0,0 +1,532 @@
+use std::{collections::HashSet, str::FromStr};
+
+use serde::Serialize;
+use serde_json::{json, Value};
+use sqlx::types::BigDecimal;
+
+use crate::error::{AppError, AppResult};
+
+use super::{
+	recipe::{Recipe, RecipeCreation, RecipeIngredient, RecipeStep},
+	tag::Tag,
+	unit,
+};
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RecipeImport {
+	pub recipe: RecipeCreation,
+	/// Images are not fetched, so the client has to upload this one itself if wanted
+	pub image_url: Option<String>,
+}
+
+/// Reads a schema.org Recipe from either a raw JSON-LD document, or an HTML page embedding one
+pub fn import(document: &str) -> AppResult<RecipeImport> {
+	let blocks = match serde_json::from_str::<Value>(document) {
+		Ok(json) => vec![json],
+		Err(_) => json_ld_blocks(document),
+	};
+	let recipe = blocks
+		.iter()
+		.find_map(find_recipe)
+		.ok_or(AppError::bad_request("No schema.org Recipe found"))?;
+	Ok(RecipeImport {
+		recipe: RecipeCreation {
+			name: text(recipe.get("name")).unwrap_or_default(),
+			description: text(recipe.get("description")).unwrap_or_default(),
+			image_id: None,
+			time_estimate_active: duration_hours(recipe.get("prepTime")),
+			time_estimate_total: duration_hours(recipe.get("totalTime")).or_else(|| {
+				let prep = duration_hours(recipe.get("prepTime"))?;
+				let cook = duration_hours(recipe.get("cookTime"))?;
+				Some(prep + cook)
+			}),
+			source_url: text(recipe.get("url")),
+			servings: recipe.get("recipeYield").and_then(servings),
+			ingredients: strings(
+				recipe
+					.get("recipeIngredient")
+					.or_else(|| recipe.get("ingredients")),
+			)
+			.iter()
+			.map(|i| parse_ingredient(i))
+			.collect(),
+			steps: recipe
+				.get("recipeInstructions")
+				.map(instructions)
+				.unwrap_or_default()
+				.into_iter()
+				.map(|description| RecipeStep {
+					description,
+					image_id: None,
+				})
+				.collect(),
+			tags: keywords(recipe.get("keywords")),
+		},
+		image_url: recipe.get("image").and_then(image_url),
+	})
+}
+
+/// Pulls the contents of every `<script type="application/ld+json">` tag out of an HTML page
+fn json_ld_blocks(html: &str) -> Vec<Value> {
+	let lowercase = html.to_ascii_lowercase();
+	let mut blocks = Vec::new();
+	let mut position = 0;
+	while let Some(start) = lowercase[position..].find("<script") {
+		let tag_start = position + start;
+		let Some(tag_end) = lowercase[tag_start..].find('>').map(|e| tag_start + e + 1) else {
+			break;
+		};
+		let Some(content_end) = lowercase[tag_end..].find("</script").map(|e| tag_end + e) else {
+			break;
+		};
+		if lowercase[tag_start..tag_end].contains("application/ld+json") {
+			if let Ok(json) = serde_json::from_str(&html[tag_end..content_end]) {
+				blocks.push(json);
+			}
+		}
+		position = content_end;
+	}
+	blocks
+}
+
+fn is_recipe(value: &Value) -> bool {
+	match value.get("@type") {
+		Some(Value::String(t)) => t == "Recipe",
+		Some(Value::Array(types)) => types.iter().any(|t| t == "Recipe"),
+		_ => false,
+	}
+}
+
+/// Recipes may be at the top level, inside an array, or inside an `@graph`
+fn find_recipe(value: &Value) -> Option<&Value> {
+	match value {
+		Value::Array(items) => items.iter().find_map(find_recipe),
+		Value::Object(_) if is_recipe(value) => Some(value),
+		Value::Object(object) => object.get("@graph").and_then(find_recipe),
+		_ => None,
+	}
+}
+
+fn decode_entities(text: &str) -> String {
+	text.replace("&nbsp;", " ")
+		.replace("&quot;", "\"")
+		.replace("&#39;", "'")
+		.replace("&#039;", "'")
+		.replace("&apos;", "'")
+		.replace("&lt;", "<")
+		.replace("&gt;", ">")
+		.replace("&amp;", "&")
+		.trim()
+		.to_string()
+}
+
+fn text(value: Option<&Value>) -> Option<String> {
+	match value? {
+		Value::String(s) => Some(decode_entities(s)).filter(|s| !s.is_empty()),
+		Value::Number(n) => Some(n.to_string()),
+		Value::Array(items) => text(items.first()),
+		_ => None,
+	}
+}
+
+fn strings(value: Option<&Value>) -> Vec<String> {
+	match value {
+		Some(Value::Array(items)) => items.iter().filter_map(|i| text(Some(i))).collect(),
+		Some(other) => text(Some(other)).into_iter().collect(),
+		None => Vec::new(),
+	}
+}
+
+fn keywords(value: Option<&Value>) -> Vec<String> {
+	let mut seen = HashSet::new();
+	strings(value)
+		.iter()
+		.flat_map(|k| k.split(','))
+		.map(Tag::normalize)
+		.filter(|k| Tag::validate(k).is_ok() && seen.insert(k.clone()))
+		.collect()
+}
+
+fn image_url(value: &Value) -> Option<String> {
+	match value {
+		Value::String(url) => Some(url.clone()),
+		Value::Array(images) => images.first().and_then(image_url),
+		Value::Object(image) => image.get("url").and_then(image_url),
+		_ => None,
+	}
+}
+
+fn servings(value: &Value) -> Option<i32> {
+	let yields = match value {
+		Value::Number(n) => return n.as_i64().and_then(|n| i32::try_from(n).ok()),
+		Value::Array(items) => return items.iter().find_map(servings),
+		Value::String(s) => s,
+		_ => return None,
+	};
+	let digits: String = yields
+		.trim()
+		.chars()
+		.take_while(|c| c.is_ascii_digit())
+		.collect();
+	digits.parse().ok().filter(|s| (1..=1000).contains(s))
+}
+
+/// Instructions may be a single string, a list of strings, a list of `HowToStep`s,
+/// or `HowToSection`s containing any of those
+fn instructions(value: &Value) -> Vec<String> {
+	match value {
+		Value::String(s) => s
+			.lines()
+			.map(decode_entities)
+			.filter(|s| !s.is_empty())
+			.collect(),
+		Value::Array(items) => items.iter().flat_map(instructions).collect(),
+		Value::Object(object) => {
+			if let Some(items) = object.get("itemListElement") {
+				instructions(items)
+			} else {
+				text(object.get("text").or_else(|| object.get("name")))
+					.into_iter()
+					.collect()
+			}
+		}
+		_ => Vec::new(),
+	}
+}
+
+/// Parses an ISO-8601 duration like `PT1H30M` into hours
+fn duration_hours(value: Option<&Value>) -> Option<BigDecimal> {
+	let duration = value?.as_str()?.trim().to_ascii_uppercase();
+	let duration = duration.strip_prefix('P')?;
+	let mut minutes = BigDecimal::from(0);
+	let mut number = String::new();
+	let mut in_time = false;
+	for c in duration.chars() {
+		match c {
+			'0'..='9' | '.' => number.push(c),
+			'T' => in_time = true,
+			_ => {
+				let amount = BigDecimal::from_str(&number).ok()?;
+				number.clear();
+				minutes += match (c, in_time) {
+					('D', false) => amount * BigDecimal::from(24 * 60),
+					('H', true) => amount * BigDecimal::from(60),
+					('M', true) => amount,
+					('S', true) => amount / BigDecimal::from(60),
+					_ => return None,
+				};
+			}
+		}
+	}
+	let hours = (minutes / BigDecimal::from(60)).round(2);
+	Some(hours).filter(|h| h > &BigDecimal::from(0))
+}
+
+fn vulgar_fraction(c: char) -> Option<&'static str> {
+	match c {
+		'½' => Some("0.5"),
+		'⅓' => Some("0.33"),
+		'⅔' => Some("0.67"),
+		'¼' => Some("0.25"),
+		'¾' => Some("0.75"),
+		'⅕' => Some("0.2"),
+		'⅛' => Some("0.125"),
+		_ => None,
+	}
+}
+
+/// Parses a single number, like `2`, `1.5`, `1,5`, `1/2`, `½` or `2½`
+fn parse_number(token: &str) -> Option<BigDecimal> {
+	if let Some((numerator, denominator)) = token.split_once('/') {
+		let numerator = BigDecimal::from_str(numerator).ok()?;
+		let denominator = BigDecimal::from_str(denominator).ok()?;
+		if denominator == BigDecimal::from(0) {
+			return None;
+		}
+		return Some(numerator / denominator);
+	}
+	let mut whole = String::new();
+	let mut fraction = BigDecimal::from(0);
+	for c in token.chars() {
+		match vulgar_fraction(c) {
+			Some(f) => fraction += BigDecimal::from_str(f).ok()?,
+			None => whole.push(if c == ',' { '.' } else { c }),
+		}
+	}
+	if whole.is_empty() {
+		return Some(fraction).filter(|f| f > &BigDecimal::from(0));
+	}
+	Some(BigDecimal::from_str(&whole).ok()? + fraction)
+}
+
+/// The first number of a range like `2-3`
+fn lower_bound(token: &str) -> &str {
+	token.split(['-', '–']).next().unwrap_or(token)
+}
+
+/// Whether a number is only a fraction, like `1/2` or `½`, which can follow a whole number
+fn is_fraction(token: &str) -> bool {
+	let mut chars = token.chars();
+	token.contains('/')
+		|| matches!((chars.next(), chars.next()), (Some(c), None) if vulgar_fraction(c).is_some())
+}
+
+/// Splits an ingredient line like "2 1/2 cups flour" into quantity, unit and name.
+/// Lines that cannot be understood become a single piece named after the whole line.
+pub fn parse_ingredient(line: &str) -> RecipeIngredient {
+	let line = decode_entities(line);
+	// Quantities are often written right next to their unit, like "200g"
+	let tokens: Vec<&str> = line
+		.split_whitespace()
+		.flat_map(|token| {
+			match token.find(|c: char| c.is_alphabetic() && vulgar_fraction(c).is_none()) {
+				Some(split) if split > 0 && parse_number(&token[..split]).is_some() => {
+					vec![&token[..split], &token[split..]]
+				}
+				_ => vec![token],
+			}
+		})
+		.collect();
+
+	// Quantities can be mixed numbers like "1 1/2", and ranges like "2-3" use the lower bound.
+	// Any other number after the first belongs to the name, like in "3 28-ounce cans".
+	let mut quantity = tokens.first().and_then(|t| parse_number(lower_bound(t)));
+	let mut position = usize::from(quantity.is_some());
+	if let Some(whole) = quantity.as_ref().filter(|q| q.is_integer()) {
+		let fraction = tokens
+			.get(1)
+			.map(|t| lower_bound(t))
+			.filter(|t| is_fraction(t))
+			.and_then(parse_number);
+		if let Some(fraction) = fraction {
+			quantity = Some(whole + fraction);
+			position += 1;
+		}
+	}
+	let Some(quantity) = quantity.filter(|q| q > &BigDecimal::from(0)) else {
+		return RecipeIngredient {
+			quantity: BigDecimal::from(1),
+			unit: "pcs".to_string(),
+			name: line,
+		};
+	};
+
+	// Units may be two words, like "fl oz", and are often abbreviated with a dot
+	let clean = |t: &str| t.trim_end_matches('.').to_string();
+	let two_words = tokens
+		.get(position..position + 2)
+		.map(|t| format!("{} {}", t[0], clean(t[1])));
+	let (unit, rest) = match two_words.as_deref().and_then(unit::lookup) {
+		Some(unit) => (unit.symbol.to_string(), position + 2),
+		None => match tokens.get(position).and_then(|t| unit::lookup(&clean(t))) {
+			Some(unit) => (unit.symbol.to_string(), position + 1),
+			None => ("pcs".to_string(), position),
+		},
+	};
+	let name = tokens[rest.min(tokens.len())..].join(" ");
+	let name = name.strip_prefix("of ").unwrap_or(&name).trim().to_string();
+	RecipeIngredient {
+		quantity: quantity.round(2),
+		unit,
+		name: if name.is_empty() { line } else { name },
+	}
+}
+
+/// Formats hours as an ISO-8601 duration like `PT1H30M`
+fn iso_duration(hours: &BigDecimal) -> String {
+	let minutes = (hours * BigDecimal::from(60)).round(0);
+	let (hours, minutes) = (
+		(&minutes / BigDecimal::from(60)).with_scale(0),
+		minutes % BigDecimal::from(60),
+	);
+	match (hours == BigDecimal::from(0), minutes == BigDecimal::from(0)) {
+		(true, _) => format!("PT{}M", minutes),
+		(false, true) => format!("PT{}H", hours),
+		(false, false) => format!("PT{}H{}M", hours, minutes),
+	}
+}
+
+/// Renders a recipe as a schema.org Recipe document.
+/// `public_url` is where the frontend is reachable, with the API under `/api`.
+pub fn export(recipe: &Recipe, author: &str, public_url: &str) -> Value {
+	let metadata = &recipe.metadata;
+	let image_url = |id: &uuid::Uuid| format!("{}/api/image/{}", public_url, id);
+	let mut document = json!({
+		"@context": "https://schema.org",
+		"@type": "Recipe",
+		"@id": format!("{}/recipe/{}", public_url, metadata.id),
+		"url": format!("{}/recipe/{}", public_url, metadata.id),
+		"name": metadata.title,
+		"description": metadata.description,
+		"author": {
+			"@type": "Person",
+			"name": author,
+		},
+		"datePublished": metadata.created_at.and_utc().to_rfc3339(),
+		"dateModified": metadata.edited_at.and_utc().to_rfc3339(),
+		"recipeIngredient": recipe
+			.ingredients
+			.iter()
+			.map(|i| format!("{} {} {}", i.quantity.normalized(), i.unit, i.name))
+			.collect::<Vec<_>>(),
+		"recipeInstructions": recipe
+			.steps
+			.iter()
+			.enumerate()
+			.map(|(num, step)| {
+				let mut how_to = json!({
+					"@type": "HowToStep",
+					"position": num + 1,
+					"text": step.description,
+				});
+				if let Some(id) = &step.image_id {
+					how_to["image"] = json!(image_url(id));
+				}
+				how_to
+			})
+			.collect::<Vec<_>>(),
+	});
+	if let Some(id) = &metadata.image_id {
+		document["image"] = json!([image_url(id)]);
+	}
+	if let Some(active) = &metadata.time_estimate_active {
+		document["prepTime"] = json!(iso_duration(active));
+	}
+	if let Some(total) = &metadata.time_estimate_total {
+		document["totalTime"] = json!(iso_duration(total));
+	}
+	if let Some(servings) = metadata.servings {
+		document["recipeYield"] = json!(format!("{} servings", servings));
+	}
+	if !metadata.tags.is_empty() {
+		document["keywords"] = json!(metadata.tags.join(", "));
+	}
+	if let Some(source_url) = &metadata.source_url {
+		document["isBasedOn"] = json!(source_url);
+	}
+	document
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn parsed(line: &str) -> (String, String, String) {
+		let ingredient = parse_ingredient(line);
+		(
+			ingredient.quantity.normalized().to_string(),
+			ingredient.unit,
+			ingredient.name,
+		)
+	}
+
+	fn expect(line: &str, quantity: &str, unit: &str, name: &str) {
+		assert_eq!(
+			parsed(line),
+			(quantity.to_string(), unit.to_string(), name.to_string()),
+			"parsing {:?}",
+			line
+		);
+	}
+
+	#[test]
+	fn ingredient_quantities() {
+		expect("2 cups flour", "2", "cup", "flour");
+		expect("1.5 kg potatoes", "1.5", "kg", "potatoes");
+		expect("1,5 l milk", "1.5", "l", "milk");
+		expect("200g butter", "200", "g", "butter");
+		expect("3 eggs", "3", "pcs", "eggs");
+	}
+
+	#[test]
+	fn ingredient_fractions() {
+		expect("1/2 tsp salt", "0.5", "tsp", "salt");
+		expect("½ cup sugar", "0.5", "cup", "sugar");
+		expect("2½ cups flour", "2.5", "cup", "flour");
+		expect("1 1/2 cups flour", "1.5", "cup", "flour");
+		expect("2 ¼ cups water", "2.25", "cup", "water");
+	}
+
+	#[test]
+	fn ingredient_ranges_use_lower_bound() {
+		expect("2-3 cloves garlic", "2", "clove", "garlic");
+		expect("1–2 tbsp oil", "1", "tbsp", "oil");
+	}
+
+	#[test]
+	fn ingredient_numbers_in_name_are_kept() {
+		expect(
+			"3 28-ounce cans tomatoes",
+			"3",
+			"pcs",
+			"28-ounce cans tomatoes",
+		);
+		expect("2 14 oz cans beans", "2", "pcs", "14 oz cans beans");
+		expect("1.5 1/2 cups flour", "1.5", "pcs", "1/2 cups flour");
+	}
+
+	#[test]
+	fn ingredient_units() {
+		expect("2 fl oz cream", "2", "fl oz", "cream");
+		expect("1 tbsp. honey", "1", "tbsp", "honey");
+		expect("100 g of cheese", "100", "g", "cheese");
+	}
+
+	#[test]
+	fn ingredient_without_quantity() {
+		expect("salt to taste", "1", "pcs", "salt to taste");
+		expect("0 eggs", "1", "pcs", "0 eggs");
+	}
+
+	fn hours(duration: &str) -> Option<String> {
+		duration_hours(Some(&json!(duration))).map(|h| h.normalized().to_string())
+	}
+
+	#[test]
+	fn durations() {
+		assert_eq!(hours("PT1H30M").as_deref(), Some("1.5"));
+		assert_eq!(hours("PT45M").as_deref(), Some("0.75"));
+		assert_eq!(hours("pt2h").as_deref(), Some("2"));
+		assert_eq!(hours("P1DT2H").as_deref(), Some("26"));
+		assert_eq!(hours("PT90S").as_deref(), Some("0.03"));
+		assert_eq!(hours("PT1.5H").as_deref(), Some("1.5"));
+	}
+
+	#[test]
+	fn invalid_durations() {
+		assert_eq!(hours("1H30M"), None);
+		assert_eq!(hours("PT0M"), None);
+		assert_eq!(hours("PTXM"), None);
+		assert_eq!(hours("P2H"), None);
+		assert_eq!(duration_hours(Some(&json!(30))), None);
+		assert_eq!(duration_hours(None), None);
+	}
+
+	#[test]
+	fn keywords_are_deduplicated() {
+		assert_eq!(
+			keywords(Some(&json!("Vegan, Quick, vegan"))),
+			vec!["vegan", "quick"]
+		);
+		assert_eq!(
+			keywords(Some(&json!(["Dinner", " dinner ", "", "Easy"]))),
+			vec!["dinner", "easy"]
+		);
+	}
+
+	#[test]
+	fn keywords_are_limited_by_characters() {
+		let long = "é".repeat(32);
+		assert_eq!(keywords(Some(&json!(long))), vec![long.clone()]);
+		assert!(keywords(Some(&json!(format!("{}e", long)))).is_empty());
+	}
+
+	#[test]
+	fn durations_round_trip() {
+		for duration in ["PT15M", "PT1H", "PT2H45M"] {
+			let hours = duration_hours(Some(&json!(duration))).unwrap();
+			assert_eq!(iso_duration(&hours), duration);
+		}
+	}
+}
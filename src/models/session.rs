@@ -0,0 +1,99 @@
+use chrono::naive::serde::ts_seconds;
+use serde::Serialize;
+use sqlx::{types::chrono::NaiveDateTime, PgPool};
+use uuid::Uuid;
+
+use crate::error::{AppError, AppResult};
+
+use super::user::token_digest;
+
+/// When sessions stop being valid
+#[derive(Debug, Clone, Copy)]
+pub struct SessionExpiry {
+	/// Hours a session may go unused
+	pub idle_hours: i32,
+	/// Hours a session may exist at all, no matter how often it is used
+	pub max_age_hours: i32,
+}
+
+#[derive(Debug, Clone, Serialize, sqlx::FromRow)]
+#[serde(rename_all = "camelCase")]
+pub struct Session {
+	pub id: Uuid,
+	#[serde(with = "ts_seconds")]
+	pub created_at: NaiveDateTime,
+	#[serde(with = "ts_seconds")]
+	pub last_used: NaiveDateTime,
+	pub user_agent: Option<String>,
+	pub ip_address: Option<String>,
+	/// Whether this is the session the list was requested with
+	pub current: bool,
+}
+
+impl Session {
+	pub async fn list(pool: &PgPool, user_id: &Uuid, current_token: &str) -> AppResult<Vec<Self>> {
+		sqlx::query_as!(
+			Session,
+			r#"
+			SELECT id, created_at, last_used, user_agent, ip_address, token_hash = $2 AS "current!"
+			FROM user_tokens
+			WHERE user_id = $1
+			ORDER BY last_used DESC
+			"#,
+			user_id,
+			token_digest(current_token)
+		)
+		.fetch_all(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to get sessions"))
+	}
+
+	pub async fn revoke(pool: &PgPool, user_id: &Uuid, id: &Uuid) -> AppResult<()> {
+		let result = sqlx::query!(
+			r#"
+			DELETE FROM user_tokens
+			WHERE user_id = $1 AND id = $2
+			"#,
+			user_id,
+			id
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to revoke session"))?;
+		if result.rows_affected() == 0 {
+			return Err(AppError::not_found("Session not found"));
+		}
+		Ok(())
+	}
+
+	pub async fn revoke_token(pool: &PgPool, token: &str) -> AppResult<()> {
+		sqlx::query!(
+			r#"
+			DELETE FROM user_tokens
+			WHERE token_hash = $1
+			"#,
+			token_digest(token)
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to revoke session"))?;
+		Ok(())
+	}
+
+	/// Deletes sessions that can no longer be used, returning how many there were
+	pub async fn purge_expired(pool: &PgPool, expiry: &SessionExpiry) -> AppResult<u64> {
+		let result = sqlx::query!(
+			r#"
+			DELETE FROM user_tokens
+			WHERE last_used <= NOW() - make_interval(hours => $1)
+				OR created_at <= NOW() - make_interval(hours => $2)
+			"#,
+			expiry.idle_hours,
+			expiry.max_age_hours
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to purge sessions"))?;
+		Ok(result.rows_affected())
+	}
+}
@@ -0,0 +1,187 @@
+use serde::Serialize;
+use sqlx::{PgConnection, PgPool};
+use uuid::Uuid;
+
+use crate::error::{AppError, AppResult};
+
+#[derive(Debug, Clone, sqlx::FromRow, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Tag {
+	pub id: Uuid,
+	pub name: String,
+	pub recipe_count: i64,
+}
+
+impl Tag {
+	/// Tags are stored trimmed and lowercased, so "Vegetarian " and "vegetarian" are the same tag
+	pub fn normalize(name: &str) -> String {
+		name.trim().to_lowercase()
+	}
+
+	/// Checks a tag name as it will be stored
+	pub fn validate(name: &str) -> AppResult<()> {
+		let name = Tag::normalize(name);
+		if name.is_empty() {
+			return Err(AppError::bad_request("Tags cannot be empty"));
+		}
+		if name.chars().count() > 32 {
+			return Err(AppError::bad_request(
+				"Tags cannot be longer than 32 characters",
+			));
+		}
+		// Tag filters are comma separated lists
+		if name.contains(',') {
+			return Err(AppError::bad_request("Tags cannot contain commas"));
+		}
+		Ok(())
+	}
+
+	pub async fn from_uuid(pool: &PgPool, id: &Uuid) -> AppResult<Self> {
+		sqlx::query_as!(
+			Tag,
+			r#"
+			SELECT t.id, t.name, (SELECT COUNT(*) FROM recipe_tags rt WHERE rt.tag_id = t.id) AS "recipe_count!"
+			FROM tags t
+			WHERE t.id = $1
+			"#,
+			id
+		)
+		.fetch_one(pool)
+		.await
+		.map_err(|_| AppError::not_found("Tag not found"))
+	}
+
+	pub async fn get_all(pool: &PgPool) -> AppResult<Vec<Self>> {
+		sqlx::query_as!(
+			Tag,
+			r#"
+			SELECT t.id, t.name, (SELECT COUNT(*) FROM recipe_tags rt WHERE rt.tag_id = t.id) AS "recipe_count!"
+			FROM tags t
+			ORDER BY t.name
+			"#
+		)
+		.fetch_all(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to get tags"))
+	}
+
+	/// Replaces the tags of a recipe, creating any tags that do not exist yet
+	pub async fn set_for_recipe(
+		conn: &mut PgConnection,
+		recipe_id: &Uuid,
+		tags: &[String],
+	) -> AppResult<()> {
+		sqlx::query!(
+			r#"
+			DELETE FROM recipe_tags
+			WHERE recipe_id = $1
+			"#,
+			recipe_id
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Error saving tags"))?;
+
+		for tag in tags {
+			let tag_id = sqlx::query_scalar!(
+				r#"
+				INSERT INTO tags (name)
+				VALUES ($1)
+				ON CONFLICT (name) DO UPDATE SET name = EXCLUDED.name
+				RETURNING id
+				"#,
+				Tag::normalize(tag)
+			)
+			.fetch_one(&mut *conn)
+			.await
+			.map_err(|_| AppError::internal("Error saving tags"))?;
+			sqlx::query!(
+				r#"
+				INSERT INTO recipe_tags (recipe_id, tag_id)
+				VALUES ($1, $2)
+				ON CONFLICT DO NOTHING
+				"#,
+				recipe_id,
+				tag_id
+			)
+			.execute(&mut *conn)
+			.await
+			.map_err(|_| AppError::internal("Error saving tags"))?;
+		}
+		Ok(())
+	}
+
+	pub async fn rename(&self, pool: &PgPool, name: &str) -> AppResult<Self> {
+		sqlx::query!(
+			r#"
+			UPDATE tags
+			SET name = $2
+			WHERE id = $1
+			"#,
+			self.id,
+			Tag::normalize(name)
+		)
+		.execute(pool)
+		.await
+		.map_err(|e| match e {
+			sqlx::Error::Database(e) if e.is_unique_violation() => {
+				AppError::bad_request("A tag with that name already exists")
+			}
+			_ => AppError::internal("Error renaming tag"),
+		})?;
+		Tag::from_uuid(pool, &self.id).await
+	}
+
+	/// Moves all recipes of this tag over to `target`, then deletes this tag
+	pub async fn merge_into(&self, pool: &PgPool, target: &Tag) -> AppResult<Tag> {
+		if self.id == target.id {
+			return Err(AppError::bad_request("Cannot merge a tag into itself"));
+		}
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Internal db error"))?;
+		sqlx::query!(
+			r#"
+			INSERT INTO recipe_tags (recipe_id, tag_id)
+			SELECT recipe_id, $2
+			FROM recipe_tags
+			WHERE tag_id = $1
+			ON CONFLICT DO NOTHING
+			"#,
+			self.id,
+			target.id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Error merging tags"))?;
+		sqlx::query!(
+			r#"
+			DELETE FROM tags
+			WHERE id = $1
+			"#,
+			self.id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Error merging tags"))?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Internal db error"))?;
+		Tag::from_uuid(pool, &target.id).await
+	}
+
+	pub async fn delete(&self, pool: &PgPool) -> AppResult<()> {
+		sqlx::query!(
+			r#"
+			DELETE FROM tags
+			WHERE id = $1
+			"#,
+			self.id
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Deletion failed"))?;
+		Ok(())
+	}
+}
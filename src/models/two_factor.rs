@@ -0,0 +1,381 @@
+use hmac::{Hmac, Mac};
+use rand::Rng;
+use reqwest::Url;
+use sha1::Sha1;
+use sqlx::{PgConnection, PgPool};
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+use crate::error::{AppError, AppResult};
+
+use super::user::{generate_token, token_digest, User};
+
+const TOTP_PERIOD: u64 = 30;
+const TOTP_DIGITS: u32 = 6;
+/// Codes from this many periods before or after now are accepted, to allow for clock drift
+const TOTP_SKEW: i64 = 1;
+const RECOVERY_CODE_COUNT: usize = 10;
+const CHALLENGE_VALIDITY_MINUTES: i32 = 5;
+const CHALLENGE_MAX_ATTEMPTS: i32 = 5;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+/// RFC 4648 base32 without padding, which is what authenticator apps expect secrets in
+fn base32_encode(data: &[u8]) -> String {
+	let mut encoded = String::new();
+	let mut buffer: u32 = 0;
+	let mut bits = 0;
+	for byte in data {
+		buffer = (buffer << 8) | *byte as u32;
+		bits += 8;
+		while bits >= 5 {
+			bits -= 5;
+			encoded.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+		}
+	}
+	if bits > 0 {
+		encoded.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+	}
+	encoded
+}
+
+fn base32_decode(data: &str) -> Option<Vec<u8>> {
+	let mut decoded = Vec::new();
+	let mut buffer: u32 = 0;
+	let mut bits = 0;
+	for c in data.trim_end_matches('=').bytes() {
+		let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+		buffer = (buffer << 5) | value;
+		bits += 5;
+		if bits >= 8 {
+			bits -= 8;
+			decoded.push((buffer >> bits) as u8);
+		}
+	}
+	Some(decoded)
+}
+
+/// RFC 4226 HOTP with SHA-1
+fn hotp(secret: &[u8], counter: u64) -> u32 {
+	let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap();
+	mac.update(&counter.to_be_bytes());
+	let hash = mac.finalize().into_bytes();
+	let offset = (hash[hash.len() - 1] & 0xf) as usize;
+	let code = u32::from_be_bytes([
+		hash[offset] & 0x7f,
+		hash[offset + 1],
+		hash[offset + 2],
+		hash[offset + 3],
+	]);
+	code % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Finds the RFC 6238 time step a code belongs to, if it is valid around now
+fn totp_step(secret: &str, code: &str) -> Option<i64> {
+	let secret = base32_decode(secret)?;
+	let code: u32 = code.trim().parse().ok()?;
+	let now = chrono::Utc::now().timestamp() / TOTP_PERIOD as i64;
+	(now - TOTP_SKEW..=now + TOTP_SKEW).find(|step| hotp(&secret, *step as u64).ct_eq(&code).into())
+}
+
+fn generate_recovery_code() -> String {
+	const ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+	let mut rng = rand::thread_rng();
+	let mut code: String = (0..8)
+		.map(|_| ALPHABET[rng.gen_range(0..ALPHABET.len())] as char)
+		.collect();
+	code.insert(4, '-');
+	code
+}
+
+fn normalize_recovery_code(code: &str) -> String {
+	code.trim().to_lowercase()
+}
+
+pub struct TwoFactor;
+
+impl TwoFactor {
+	/// Starts enrolment with a new secret. Returns the secret and an otpauth URI for authenticator apps.
+	/// 2FA only takes effect once a first code is confirmed.
+	pub async fn enroll(pool: &PgPool, user: &User) -> AppResult<(String, String)> {
+		if user.two_factor_enabled {
+			return Err(AppError::bad_request(
+				"Two-factor authentication is already enabled",
+			));
+		}
+		let secret = base32_encode(&rand::random::<[u8; 20]>());
+		sqlx::query!(
+			r#"
+			UPDATE users
+			SET totp_secret = $2, totp_last_step = NULL
+			WHERE id = $1
+			"#,
+			user.id,
+			secret
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to start enrolment"))?;
+
+		let mut uri = Url::parse("otpauth://totp/").unwrap();
+		uri.set_path(&format!("Cromptch:{}", user.email));
+		uri.query_pairs_mut()
+			.append_pair("secret", &secret)
+			.append_pair("issuer", "Cromptch")
+			.append_pair("algorithm", "SHA1")
+			.append_pair("digits", &TOTP_DIGITS.to_string())
+			.append_pair("period", &TOTP_PERIOD.to_string());
+		Ok((secret, uri.to_string()))
+	}
+
+	/// Finishes enrolment with a first code. Returns a fresh set of recovery codes.
+	pub async fn confirm(pool: &PgPool, user: &User, code: &str) -> AppResult<Vec<String>> {
+		if user.two_factor_enabled {
+			return Err(AppError::bad_request(
+				"Two-factor authentication is already enabled",
+			));
+		}
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Failed to start transaction"))?;
+		if !Self::check_totp(&mut tx, &user.id, code).await? {
+			return Err(AppError::bad_request("Invalid code"));
+		}
+		sqlx::query!(
+			r#"
+			UPDATE users
+			SET totp_enabled_at = NOW()
+			WHERE id = $1
+			"#,
+			user.id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to enable two-factor authentication"))?;
+		let codes = Self::replace_recovery_codes(&mut tx, &user.id).await?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		Ok(codes)
+	}
+
+	pub async fn disable(pool: &PgPool, user_id: &Uuid) -> AppResult<()> {
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Failed to start transaction"))?;
+		sqlx::query!(
+			r#"
+			UPDATE users
+			SET totp_secret = NULL, totp_enabled_at = NULL, totp_last_step = NULL
+			WHERE id = $1
+			"#,
+			user_id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to disable two-factor authentication"))?;
+		sqlx::query!(
+			r#"
+			DELETE FROM recovery_codes
+			WHERE user_id = $1
+			"#,
+			user_id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to disable two-factor authentication"))?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		Ok(())
+	}
+
+	/// Replaces all recovery codes of the user. Returns the new codes, which are only stored as digests.
+	pub async fn regenerate_recovery_codes(
+		pool: &PgPool,
+		user_id: &Uuid,
+	) -> AppResult<Vec<String>> {
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Failed to start transaction"))?;
+		let codes = Self::replace_recovery_codes(&mut tx, user_id).await?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		Ok(codes)
+	}
+
+	async fn replace_recovery_codes(
+		conn: &mut PgConnection,
+		user_id: &Uuid,
+	) -> AppResult<Vec<String>> {
+		let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+			.map(|_| generate_recovery_code())
+			.collect();
+		let digests: Vec<String> = codes.iter().map(|c| token_digest(c)).collect();
+		sqlx::query!(
+			r#"
+			DELETE FROM recovery_codes
+			WHERE user_id = $1
+			"#,
+			user_id
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to create recovery codes"))?;
+		sqlx::query!(
+			r#"
+			INSERT INTO recovery_codes (user_id, code_hash)
+			SELECT $1, * FROM UNNEST($2::TEXT[])
+			"#,
+			user_id,
+			&digests
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to create recovery codes"))?;
+		Ok(codes)
+	}
+
+	/// Checks a code from an authenticator app, or failing that a recovery code, using it up
+	pub async fn verify(pool: &PgPool, user_id: &Uuid, code: &str) -> AppResult<bool> {
+		let mut conn = pool
+			.acquire()
+			.await
+			.map_err(|_| AppError::internal("Failed to check code"))?;
+		if Self::check_totp(&mut conn, user_id, code).await? {
+			return Ok(true);
+		}
+		let unused = sqlx::query_scalar!(
+			r#"
+			SELECT code_hash
+			FROM recovery_codes
+			WHERE user_id = $1 AND used_at IS NULL
+			"#,
+			user_id
+		)
+		.fetch_all(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to check recovery code"))?;
+		// Every code is compared in full, so the time taken gives nothing away
+		let digest = token_digest(&normalize_recovery_code(code));
+		let Some(code_hash) = unused.into_iter().fold(None, |found, hash| {
+			match bool::from(hash.as_bytes().ct_eq(digest.as_bytes())) {
+				true => Some(hash),
+				false => found,
+			}
+		}) else {
+			return Ok(false);
+		};
+		let used = sqlx::query!(
+			r#"
+			UPDATE recovery_codes
+			SET used_at = NOW()
+			WHERE user_id = $1 AND code_hash = $2 AND used_at IS NULL
+			"#,
+			user_id,
+			code_hash
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to check recovery code"))?;
+		Ok(used.rows_affected() == 1)
+	}
+
+	/// Checks a code against the user's secret. Each code is only accepted once.
+	async fn check_totp(conn: &mut PgConnection, user_id: &Uuid, code: &str) -> AppResult<bool> {
+		let secret = sqlx::query_scalar!(
+			r#"
+			SELECT totp_secret
+			FROM users
+			WHERE id = $1
+			"#,
+			user_id
+		)
+		.fetch_one(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to check code"))?
+		.ok_or(AppError::bad_request(
+			"Two-factor authentication is not set up",
+		))?;
+		let Some(step) = totp_step(&secret, code) else {
+			return Ok(false);
+		};
+		let accepted = sqlx::query!(
+			r#"
+			UPDATE users
+			SET totp_last_step = $2
+			WHERE id = $1 AND (totp_last_step IS NULL OR totp_last_step < $2)
+			"#,
+			user_id,
+			step
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to check code"))?;
+		Ok(accepted.rows_affected() == 1)
+	}
+}
+
+/// A login that has passed the password check and is waiting for its second factor
+pub struct LoginChallenge;
+
+impl LoginChallenge {
+	/// Returns the challenge token, which is only stored as a digest
+	pub async fn create(pool: &PgPool, user: &User) -> AppResult<String> {
+		let token = generate_token();
+		sqlx::query!(
+			r#"
+			INSERT INTO login_challenges (token_hash, user_id, expires_at)
+			VALUES ($1, $2, NOW() + make_interval(mins => $3))
+			"#,
+			token_digest(&token),
+			user.id,
+			CHALLENGE_VALIDITY_MINUTES
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to start login"))?;
+		Ok(token)
+	}
+
+	/// Uses up one of the tries a challenge allows, returning whose login it is
+	pub async fn attempt(pool: &PgPool, token: &str) -> AppResult<User> {
+		let user_id = sqlx::query_scalar!(
+			r#"
+			UPDATE login_challenges
+			SET attempts = attempts + 1
+			WHERE token_hash = $1 AND expires_at > NOW() AND attempts < $2
+			RETURNING user_id
+			"#,
+			token_digest(token),
+			CHALLENGE_MAX_ATTEMPTS
+		)
+		.fetch_optional(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to check login"))?
+		.ok_or(AppError::unauthorized("Login expired, please log in again"))?;
+		User::from_uuid(pool, &user_id).await
+	}
+
+	/// Checks the code for an attempt, and ends the challenge if it is right
+	pub async fn complete(pool: &PgPool, token: &str, user: &User, code: &str) -> AppResult<()> {
+		if !TwoFactor::verify(pool, &user.id, code).await? {
+			return Err(AppError::unauthorized("Invalid code"));
+		}
+		sqlx::query!(
+			r#"
+			DELETE FROM login_challenges
+			WHERE token_hash = $1 OR expires_at <= NOW()
+			"#,
+			token_digest(token)
+		)
+		.execute(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to complete login"))?;
+		Ok(())
+	}
+}
@@ -0,0 +1,441 @@
+use std::str::FromStr;
+
+use sqlx::types::BigDecimal;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnitKind {
+	Mass,
+	Volume,
+	Count,
+	Temperature,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnitSystem {
+	Metric,
+	Imperial,
+	/// Units used the same way in both systems, like spoons and pieces
+	Neutral,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Rounding {
+	/// Whole units, or tenths below 10
+	Whole,
+	/// Nearest `1 / n`
+	Fraction(i64),
+}
+
+#[derive(Debug)]
+pub struct Unit {
+	pub symbol: &'static str,
+	pub kind: UnitKind,
+	pub system: UnitSystem,
+	/// How many grams, milliliters or pieces one of this unit is
+	to_base: &'static str,
+	rounding: Rounding,
+	aliases: &'static [&'static str],
+}
+
+const UNITS: &[Unit] = &[
+	// Mass, based on grams
+	Unit {
+		symbol: "mg",
+		kind: UnitKind::Mass,
+		system: UnitSystem::Metric,
+		to_base: "0.001",
+		rounding: Rounding::Whole,
+		aliases: &["milligram", "milligrams"],
+	},
+	Unit {
+		symbol: "g",
+		kind: UnitKind::Mass,
+		system: UnitSystem::Metric,
+		to_base: "1",
+		rounding: Rounding::Whole,
+		aliases: &["gr", "gram", "grams", "gramme", "grammes"],
+	},
+	Unit {
+		symbol: "kg",
+		kind: UnitKind::Mass,
+		system: UnitSystem::Metric,
+		to_base: "1000",
+		rounding: Rounding::Fraction(100),
+		aliases: &["kgs", "kilo", "kilos", "kilogram", "kilograms"],
+	},
+	Unit {
+		symbol: "oz",
+		kind: UnitKind::Mass,
+		system: UnitSystem::Imperial,
+		to_base: "28.349523125",
+		rounding: Rounding::Fraction(4),
+		aliases: &["ounce", "ounces"],
+	},
+	Unit {
+		symbol: "lb",
+		kind: UnitKind::Mass,
+		system: UnitSystem::Imperial,
+		to_base: "453.59237",
+		rounding: Rounding::Fraction(4),
+		aliases: &["lbs", "pound", "pounds"],
+	},
+	// Volume, based on milliliters
+	Unit {
+		symbol: "ml",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Metric,
+		to_base: "1",
+		rounding: Rounding::Whole,
+		aliases: &["milliliter", "milliliters", "millilitre", "millilitres"],
+	},
+	Unit {
+		symbol: "cl",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Metric,
+		to_base: "10",
+		rounding: Rounding::Whole,
+		aliases: &["centiliter", "centiliters", "centilitre", "centilitres"],
+	},
+	Unit {
+		symbol: "dl",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Metric,
+		to_base: "100",
+		rounding: Rounding::Fraction(10),
+		aliases: &["deciliter", "deciliters", "decilitre", "decilitres"],
+	},
+	Unit {
+		symbol: "l",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Metric,
+		to_base: "1000",
+		rounding: Rounding::Fraction(100),
+		aliases: &["liter", "liters", "litre", "litres"],
+	},
+	Unit {
+		symbol: "tsp",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Neutral,
+		to_base: "4.92892159375",
+		rounding: Rounding::Fraction(4),
+		aliases: &["tsps", "teaspoon", "teaspoons"],
+	},
+	Unit {
+		symbol: "tbsp",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Neutral,
+		to_base: "14.78676478125",
+		rounding: Rounding::Fraction(4),
+		aliases: &["tbs", "tbsps", "tablespoon", "tablespoons"],
+	},
+	Unit {
+		symbol: "fl oz",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Imperial,
+		to_base: "29.5735295625",
+		rounding: Rounding::Fraction(4),
+		aliases: &["floz", "fl. oz", "fluid ounce", "fluid ounces"],
+	},
+	Unit {
+		symbol: "cup",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Imperial,
+		to_base: "236.5882365",
+		rounding: Rounding::Fraction(4),
+		aliases: &["cups"],
+	},
+	Unit {
+		symbol: "pt",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Imperial,
+		to_base: "473.176473",
+		rounding: Rounding::Fraction(4),
+		aliases: &["pint", "pints"],
+	},
+	Unit {
+		symbol: "qt",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Imperial,
+		to_base: "946.352946",
+		rounding: Rounding::Fraction(4),
+		aliases: &["quart", "quarts"],
+	},
+	Unit {
+		symbol: "gal",
+		kind: UnitKind::Volume,
+		system: UnitSystem::Imperial,
+		to_base: "3785.411784",
+		rounding: Rounding::Fraction(4),
+		aliases: &["gallon", "gallons"],
+	},
+	// Countable things, which are never converted
+	Unit {
+		symbol: "pcs",
+		kind: UnitKind::Count,
+		system: UnitSystem::Neutral,
+		to_base: "1",
+		rounding: Rounding::Fraction(2),
+		aliases: &["pc", "piece", "pieces", "stk", "whole"],
+	},
+	Unit {
+		symbol: "clove",
+		kind: UnitKind::Count,
+		system: UnitSystem::Neutral,
+		to_base: "1",
+		rounding: Rounding::Fraction(2),
+		aliases: &["cloves"],
+	},
+	Unit {
+		symbol: "slice",
+		kind: UnitKind::Count,
+		system: UnitSystem::Neutral,
+		to_base: "1",
+		rounding: Rounding::Fraction(2),
+		aliases: &["slices"],
+	},
+	Unit {
+		symbol: "can",
+		kind: UnitKind::Count,
+		system: UnitSystem::Neutral,
+		to_base: "1",
+		rounding: Rounding::Fraction(2),
+		aliases: &["cans", "tin", "tins"],
+	},
+	Unit {
+		symbol: "pinch",
+		kind: UnitKind::Count,
+		system: UnitSystem::Neutral,
+		to_base: "1",
+		rounding: Rounding::Fraction(2),
+		aliases: &["pinches"],
+	},
+	// Temperatures do not scale linearly, so they are converted separately
+	Unit {
+		symbol: "°C",
+		kind: UnitKind::Temperature,
+		system: UnitSystem::Metric,
+		to_base: "1",
+		rounding: Rounding::Fraction(1),
+		aliases: &["celsius", "degc", "deg c", "degrees celsius"],
+	},
+	Unit {
+		symbol: "°F",
+		kind: UnitKind::Temperature,
+		system: UnitSystem::Imperial,
+		to_base: "1",
+		rounding: Rounding::Fraction(1),
+		aliases: &["fahrenheit", "degf", "deg f", "degrees fahrenheit"],
+	},
+];
+
+/// Grams per milliliter for ingredients commonly measured by volume in imperial recipes.
+/// Names are matched against the last words of an ingredient, and the longest match wins.
+const DENSITIES: &[(&str, &str)] = &[
+	("brown sugar", "0.93"),
+	("powdered sugar", "0.51"),
+	("icing sugar", "0.51"),
+	("sugar", "0.85"),
+	("flour", "0.53"),
+	("butter", "0.96"),
+	("peanut butter", "1.08"),
+	("oats", "0.38"),
+	("rice", "0.79"),
+	("honey", "1.42"),
+	("cocoa", "0.42"),
+	("cocoa powder", "0.42"),
+	("salt", "1.2"),
+];
+
+fn decimal(value: &str) -> BigDecimal {
+	BigDecimal::from_str(value).unwrap()
+}
+
+fn symbol(symbol: &str) -> &'static Unit {
+	UNITS.iter().find(|u| u.symbol == symbol).unwrap()
+}
+
+/// Looks up an ingredient by the words its name ends with, so "all-purpose flour" is flour
+/// but "rice vinegar" is not rice
+fn density_of(ingredient: &str) -> Option<BigDecimal> {
+	// Notes like "butter, softened" or "flour (sifted)" are not part of the name
+	let name = ingredient
+		.split([',', '('])
+		.next()
+		.unwrap_or_default()
+		.to_lowercase();
+	let words: Vec<&str> = name
+		.split(|c: char| !c.is_alphanumeric())
+		.filter(|w| !w.is_empty())
+		.collect();
+	DENSITIES
+		.iter()
+		.filter(|(known, _)| words.ends_with(&known.split(' ').collect::<Vec<_>>()))
+		.max_by_key(|(known, _)| known.len())
+		.map(|(_, density)| decimal(density))
+}
+
+/// Finds a known unit by its symbol or one of its aliases, ignoring case
+pub fn lookup(name: &str) -> Option<&'static Unit> {
+	let name = name.trim();
+	UNITS.iter().find(|u| {
+		u.symbol.eq_ignore_ascii_case(name)
+			|| u.aliases.iter().any(|a| a.eq_ignore_ascii_case(name))
+	})
+}
+
+/// Returns the canonical symbol for known units, and the trimmed input for anything else
+pub fn normalize(name: &str) -> String {
+	match lookup(name) {
+		Some(unit) => unit.symbol.to_string(),
+		None => name.trim().to_string(),
+	}
+}
+
+/// Rounds a quantity to a precision that makes sense for its unit, never all the way down to zero
+pub fn round(quantity: &BigDecimal, unit: &str) -> BigDecimal {
+	let rounding = lookup(unit).map_or(Rounding::Fraction(100), |u| u.rounding);
+	let steps = match rounding {
+		Rounding::Whole if quantity < &BigDecimal::from(10) => 10,
+		Rounding::Whole => 1,
+		Rounding::Fraction(n) => n,
+	};
+	let steps = BigDecimal::from(steps);
+	let mut rounded = (quantity * &steps).round(0);
+	if rounded == BigDecimal::from(0) && quantity > &BigDecimal::from(0) {
+		rounded = BigDecimal::from(1);
+	}
+	(rounded / steps).normalized()
+}
+
+/// Converts a quantity of an ingredient into the given unit system. Unknown units are left as they are.
+pub fn convert(
+	quantity: &BigDecimal,
+	unit: &str,
+	ingredient: &str,
+	target: UnitSystem,
+) -> (BigDecimal, String) {
+	let unchanged = (quantity.clone(), unit.to_string());
+	let Some(from) = lookup(unit) else {
+		return unchanged;
+	};
+
+	if from.kind == UnitKind::Temperature {
+		let converted = match (from.system, target) {
+			(UnitSystem::Metric, UnitSystem::Imperial) => {
+				(quantity * decimal("9") / decimal("5") + decimal("32"), "°F")
+			}
+			(UnitSystem::Imperial, UnitSystem::Metric) => (
+				(quantity - decimal("32")) * decimal("5") / decimal("9"),
+				"°C",
+			),
+			_ => return unchanged,
+		};
+		return (round(&converted.0, converted.1), converted.1.to_string());
+	}
+
+	let base = quantity * decimal(from.to_base);
+	let to = match (from.kind, target) {
+		(UnitKind::Count, _) | (_, UnitSystem::Neutral) => return unchanged,
+		(UnitKind::Volume, UnitSystem::Metric) => match density_of(ingredient) {
+			// Metric recipes weigh dry ingredients instead of measuring their volume
+			Some(density) => {
+				let grams = &base * density;
+				let to = match grams >= decimal("1000") {
+					true => symbol("kg"),
+					false => symbol("g"),
+				};
+				return (
+					round(&(grams / decimal(to.to_base)), to.symbol),
+					to.symbol.to_string(),
+				);
+			}
+			None if from.system != UnitSystem::Metric => match base >= decimal("1000") {
+				true => symbol("l"),
+				false => symbol("ml"),
+			},
+			None => return unchanged,
+		},
+		(UnitKind::Mass, UnitSystem::Metric) if from.system != UnitSystem::Metric => {
+			match base >= decimal("1000") {
+				true => symbol("kg"),
+				false => symbol("g"),
+			}
+		}
+		(UnitKind::Mass, UnitSystem::Imperial) if from.system != UnitSystem::Imperial => {
+			match base >= decimal(symbol("lb").to_base) {
+				true => symbol("lb"),
+				false => symbol("oz"),
+			}
+		}
+		(UnitKind::Volume, UnitSystem::Imperial) if from.system == UnitSystem::Metric => {
+			if base < decimal(symbol("tbsp").to_base) {
+				symbol("tsp")
+			} else if base < decimal(symbol("cup").to_base) / decimal("4") {
+				symbol("tbsp")
+			} else {
+				symbol("cup")
+			}
+		}
+		_ => return unchanged,
+	};
+	let converted = base / decimal(to.to_base);
+	let rounded = round(&converted, to.symbol);
+	// Amounts too small for the target unit would be badly overstated by rounding
+	if rounded > &converted * decimal("2") {
+		return unchanged;
+	}
+	(rounded, to.symbol.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn density(ingredient: &str) -> Option<String> {
+		density_of(ingredient).map(|d| d.to_string())
+	}
+
+	#[test]
+	fn densities_match_whole_names() {
+		assert_eq!(density("flour").as_deref(), Some("0.53"));
+		assert_eq!(density("All-purpose Flour").as_deref(), Some("0.53"));
+		assert_eq!(
+			density("unsalted butter, softened").as_deref(),
+			Some("0.96")
+		);
+		assert_eq!(density("light brown sugar").as_deref(), Some("0.93"));
+		assert_eq!(
+			density("granulated sugar (sifted)").as_deref(),
+			Some("0.85")
+		);
+		assert_eq!(density("long grain rice").as_deref(), Some("0.79"));
+	}
+
+	#[test]
+	fn densities_prefer_longer_names() {
+		assert_eq!(density("powdered sugar").as_deref(), Some("0.51"));
+		assert_eq!(density("creamy peanut butter").as_deref(), Some("1.08"));
+	}
+
+	#[test]
+	fn densities_ignore_partial_matches() {
+		for ingredient in [
+			"buttermilk",
+			"rice vinegar",
+			"sugar snap peas",
+			"butternut squash",
+			"salted caramel sauce",
+			"flour tortillas",
+		] {
+			assert_eq!(density(ingredient), None, "{} has a density", ingredient);
+		}
+	}
+
+	#[test]
+	fn volumes_of_dry_ingredients_become_weights() {
+		let (quantity, unit) = convert(&decimal("1"), "cup", "flour", UnitSystem::Metric);
+		assert_eq!((quantity.to_string().as_str(), unit.as_str()), ("125", "g"));
+		let (_, unit) = convert(&decimal("1"), "cup", "buttermilk", UnitSystem::Metric);
+		assert_eq!(unit, "ml");
+	}
+}
@@ -1,21 +1,34 @@
-use std::sync::Arc;
+use std::sync::{Arc, OnceLock};
 
 use argon2::{hash_encoded, verify_encoded};
 use axum::{
 	async_trait,
 	extract::{FromRef, FromRequestParts},
-	http::request::Parts,
+	http::{request::Parts, HeaderMap},
 };
 use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::NaiveDateTime;
 use rand::RngCore;
-use sqlx::PgPool;
+use sha2::{Digest, Sha256};
+use sqlx::{PgConnection, PgPool};
+use tracing::warn;
 use uuid::Uuid;
 
 use crate::{
 	error::{AppError, AppResult},
+	external::image::ImageStorage,
 	AppState,
 };
 
+use super::{
+	api_token::{ApiToken, Scope, API_TOKEN_PREFIX},
+	image::Image,
+	session::SessionExpiry,
+};
+
+/// The placeholder account that recipes of deleted accounts can be transferred to
+pub const DELETED_USER_ID: Uuid = Uuid::nil();
+
 #[derive(Debug, Clone, sqlx::FromRow)]
 pub struct User {
 	pub id: Uuid,
@@ -23,6 +36,55 @@ pub struct User {
 	pub email: String,
 	pub password: String,
 	pub is_admin: bool,
+	pub verified_at: Option<NaiveDateTime>,
+	pub two_factor_enabled: bool,
+}
+
+/// Generates a random token for handing out to users
+pub fn generate_token() -> String {
+	// 48 bytes = 64 characters in base64
+	let mut random_bytes: [u8; 48] = [0; 48];
+	rand::thread_rng().fill_bytes(&mut random_bytes);
+	URL_SAFE_NO_PAD.encode(random_bytes)
+}
+
+/// Hex encoded SHA-256 digest of a token, for tokens that should not be stored as they are
+pub fn token_digest(token: &str) -> String {
+	format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Names that would be shadowed by other routes under `/api/user/`, or pass for staff
+const RESERVED_USERNAMES: &[&str] = &[
+	"2fa", "admin", "create", "login", "logout", "oidc", "password", "self", "sessions", "tokens",
+	"verify",
+];
+
+pub fn validate_username(username: &str) -> AppResult<()> {
+	if username.len() < 3 {
+		return Err(AppError::bad_request(
+			"Username must be at least 3 characters",
+		));
+	}
+	if RESERVED_USERNAMES.contains(&username.to_lowercase().as_str()) {
+		return Err(AppError::bad_request("This username is not available"));
+	}
+	Ok(())
+}
+
+/// Extracts the token from an `Authorization: Bearer` header
+pub fn bearer_token(headers: &HeaderMap) -> AppResult<&str> {
+	Ok(headers
+		.get("Authorization")
+		.ok_or(AppError::unauthorized("Missing authorization header"))?
+		.to_str()
+		.map_err(|_| AppError::unauthorized("Invalid authorization header"))?
+		.trim_start_matches("Bearer "))
+}
+
+pub fn hash_password(password: &str) -> AppResult<String> {
+	let salt: [u8; 16] = rand::random();
+	hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
+		.map_err(|_| AppError::internal("Failed to hash password"))
 }
 
 impl User {
@@ -30,18 +92,17 @@ impl User {
 		pool: &PgPool,
 		username: &String,
 		email: &String,
-		password: &String,
+		password: &str,
 		is_admin: &bool,
 	) -> AppResult<Self> {
-		let salt: [u8; 16] = rand::random();
-		let hash = hash_encoded(password.as_bytes(), &salt, &argon2::Config::default())
-			.map_err(|_| AppError::internal("Failed to hash password"))?;
+		let hash = hash_password(password)?;
 		let user = sqlx::query_as!(
 			User,
 			r#"
 			INSERT INTO users (username, email, password, is_admin)
 			VALUES ($1, $2, $3, $4)
-			RETURNING id, username, email, password, is_admin
+			RETURNING id, username, email, password, is_admin, verified_at,
+				totp_enabled_at IS NOT NULL AS "two_factor_enabled!"
 			"#,
 			username,
 			email,
@@ -58,7 +119,8 @@ impl User {
 		let user = sqlx::query_as!(
 			User,
 			r#"
-			SELECT id, username, email, password, is_admin
+			SELECT id, username, email, password, is_admin, verified_at,
+				totp_enabled_at IS NOT NULL AS "two_factor_enabled!"
 			FROM users
 			WHERE id = $1
 			"#,
@@ -70,15 +132,22 @@ impl User {
 		Ok(user)
 	}
 
-	pub async fn from_token(pool: &PgPool, token: &String) -> AppResult<Self> {
+	pub async fn from_token(pool: &PgPool, token: &str, expiry: &SessionExpiry) -> AppResult<Self> {
+		// Only digests of session tokens are stored
+		let digest = token_digest(token);
 		let user = sqlx::query_as!(
 			User,
 			r#"
-			SELECT u.id, u.username, u.email, u.password, u.is_admin
+			SELECT u.id, u.username, u.email, u.password, u.is_admin, u.verified_at,
+				u.totp_enabled_at IS NOT NULL AS "two_factor_enabled!"
 			FROM users u INNER JOIN user_tokens t ON u.id = t.user_id
-			WHERE t.token = $1
+			WHERE t.token_hash = $1
+				AND t.last_used > NOW() - make_interval(hours => $2)
+				AND t.created_at > NOW() - make_interval(hours => $3)
 			"#,
-			token
+			digest,
+			expiry.idle_hours,
+			expiry.max_age_hours
 		)
 		.fetch_one(pool)
 		.await
@@ -87,9 +156,9 @@ impl User {
 			r#"
 			UPDATE user_tokens
 			SET last_used = NOW()
-			WHERE token = $1
+			WHERE token_hash = $1
 			"#,
-			token
+			digest
 		)
 		.execute(pool)
 		.await
@@ -97,39 +166,55 @@ impl User {
 		Ok(user)
 	}
 
-	pub async fn from_login(pool: &PgPool, email: &String, password: &String) -> AppResult<Self> {
+	pub async fn from_login(pool: &PgPool, email: &String, password: &str) -> AppResult<Self> {
 		let user = sqlx::query_as!(
 			User,
 			r#"
-			SELECT id, username, email, password, is_admin
+			SELECT id, username, email, password, is_admin, verified_at,
+				totp_enabled_at IS NOT NULL AS "two_factor_enabled!"
 			FROM users
 			WHERE email = $1
 			"#,
 			email
 		)
-		.fetch_one(pool)
+		.fetch_optional(pool)
 		.await
-		.map_err(|_| AppError::not_found("User not found"))?;
-		let valid = verify_encoded(&user.password, password.as_bytes())
-			.map_err(|_| AppError::internal("Failed to retrieve user"))?;
-		match valid {
+		.map_err(|_| AppError::internal("Failed to retrieve user"))?;
+		let Some(user) = user else {
+			// Hash anyway, so unknown emails take as long as wrong passwords and can't be told apart
+			static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+			let dummy =
+				DUMMY_HASH.get_or_init(|| hash_password(&generate_token()).unwrap_or_default());
+			let _ = verify_encoded(dummy, password.as_bytes());
+			return Err(AppError::not_found("User not found"));
+		};
+		match user.check_password(password) {
 			true => Ok(user),
 			false => Err(AppError::not_found("User not found")),
 		}
 	}
 
-	pub async fn create_token(&self, pool: &PgPool) -> AppResult<String> {
-		// 48 bytes = 64 characters in base64
-		let mut random_bytes: [u8; 48] = [0; 48];
-		rand::thread_rng().fill_bytes(&mut random_bytes);
-		let token = URL_SAFE_NO_PAD.encode(random_bytes);
+	/// Accounts without a usable password, like the placeholder for deleted accounts, never match
+	pub fn check_password(&self, password: &str) -> bool {
+		verify_encoded(&self.password, password.as_bytes()).unwrap_or(false)
+	}
+
+	pub async fn create_token(
+		&self,
+		pool: &PgPool,
+		user_agent: Option<&str>,
+		ip_address: Option<&str>,
+	) -> AppResult<String> {
+		let token = generate_token();
 		sqlx::query!(
 			r#"
-			INSERT INTO user_tokens (token, user_id)
-			VALUES ($1, $2)
+			INSERT INTO user_tokens (token_hash, user_id, user_agent, ip_address)
+			VALUES ($1, $2, $3, $4)
 			"#,
-			&token,
-			self.id
+			token_digest(&token),
+			self.id,
+			user_agent,
+			ip_address
 		)
 		.execute(pool)
 		.await
@@ -137,13 +222,231 @@ impl User {
 		Ok(token)
 	}
 
+	pub async fn from_email(pool: &PgPool, email: &str) -> AppResult<Self> {
+		sqlx::query_as!(
+			User,
+			r#"
+			SELECT id, username, email, password, is_admin, verified_at,
+				totp_enabled_at IS NOT NULL AS "two_factor_enabled!"
+			FROM users
+			WHERE email = $1
+			"#,
+			email
+		)
+		.fetch_optional(pool)
+		.await
+		.map_err(|_| AppError::internal("Failed to retrieve user"))?
+		.ok_or(AppError::not_found("User not found"))
+	}
+
+	/// Changes the password and logs the user out everywhere, revoking API tokens as well
+	pub async fn set_password(&self, conn: &mut PgConnection, password: &str) -> AppResult<()> {
+		let hash = hash_password(password)?;
+		sqlx::query!(
+			r#"
+			UPDATE users
+			SET password = $2
+			WHERE id = $1
+			"#,
+			self.id,
+			hash
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to update password"))?;
+		sqlx::query!(
+			r#"
+			DELETE FROM user_tokens
+			WHERE user_id = $1
+			"#,
+			self.id
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to revoke tokens"))?;
+		sqlx::query!(
+			r#"
+			DELETE FROM api_tokens
+			WHERE user_id = $1
+			"#,
+			self.id
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to revoke tokens"))?;
+		Ok(())
+	}
+
+	pub async fn set_username(&self, conn: &mut PgConnection, username: &str) -> AppResult<()> {
+		sqlx::query!(
+			r#"
+			UPDATE users
+			SET username = $2
+			WHERE id = $1
+			"#,
+			self.id,
+			username
+		)
+		.execute(conn)
+		.await
+		.map_err(|e| match e {
+			sqlx::Error::Database(e) if e.is_unique_violation() => {
+				AppError::bad_request("Username is already taken")
+			}
+			_ => AppError::internal("Failed to update username"),
+		})?;
+		Ok(())
+	}
+
+	/// Changes the email address, which then needs to be verified again
+	pub async fn set_email(&self, conn: &mut PgConnection, email: &str) -> AppResult<()> {
+		sqlx::query!(
+			r#"
+			UPDATE users
+			SET email = $2, verified_at = NULL
+			WHERE id = $1
+			"#,
+			self.id,
+			email
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|e| match e {
+			sqlx::Error::Database(e) if e.is_unique_violation() => {
+				AppError::bad_request("Email address is already in use")
+			}
+			_ => AppError::internal("Failed to update email address"),
+		})?;
+		// Links sent to the old address must not verify the new one. They are expired rather than
+		// deleted so they still count towards the mail limit.
+		sqlx::query!(
+			r#"
+			UPDATE email_verifications
+			SET expires_at = NOW()
+			WHERE user_id = $1 AND expires_at > NOW()
+			"#,
+			self.id
+		)
+		.execute(&mut *conn)
+		.await
+		.map_err(|_| AppError::internal("Failed to update email address"))?;
+		Ok(())
+	}
+
+	/// Deletes the account. Its recipes and images are either deleted along with it,
+	/// or handed over to the placeholder for deleted accounts.
+	pub async fn delete(
+		&self,
+		pool: &PgPool,
+		storage: &dyn ImageStorage,
+		transfer_recipes: bool,
+	) -> AppResult<()> {
+		let mut tx = pool
+			.begin()
+			.await
+			.map_err(|_| AppError::internal("Failed to start transaction"))?;
+		let mut images = Vec::new();
+		if !transfer_recipes {
+			// The rows would go with the account anyway, but the files have to be found first
+			images = sqlx::query_as!(
+				Image,
+				r#"
+				DELETE FROM images
+				WHERE owner = $1
+				RETURNING id, delete_token, owner, width, height
+				"#,
+				self.id
+			)
+			.fetch_all(&mut *tx)
+			.await
+			.map_err(|_| AppError::internal("Failed to delete images"))?;
+		} else {
+			sqlx::query!(
+				r#"
+				UPDATE recipes
+				SET author = $2
+				WHERE author = $1
+				"#,
+				self.id,
+				DELETED_USER_ID
+			)
+			.execute(&mut *tx)
+			.await
+			.map_err(|_| AppError::internal("Failed to transfer recipes"))?;
+			sqlx::query!(
+				r#"
+				UPDATE images
+				SET owner = $2
+				WHERE owner = $1
+				"#,
+				self.id,
+				DELETED_USER_ID
+			)
+			.execute(&mut *tx)
+			.await
+			.map_err(|_| AppError::internal("Failed to transfer images"))?;
+		}
+		sqlx::query!(
+			r#"
+			DELETE FROM users
+			WHERE id = $1
+			"#,
+			self.id
+		)
+		.execute(&mut *tx)
+		.await
+		.map_err(|_| AppError::internal("Failed to delete user"))?;
+		tx.commit()
+			.await
+			.map_err(|_| AppError::internal("Failed to commit transaction"))?;
+		// Files are only removed once the account is gone for sure. Any left behind are
+		// no longer referenced, so nothing will serve them.
+		for image in images {
+			if let Err(e) = storage.delete(&image.id, &image.delete_token).await {
+				warn!("Failed to delete image {} from storage: {}", image.id, e);
+			}
+		}
+		Ok(())
+	}
+
+	/// Authenticates a request without withholding admin rights from admins who still need to
+	/// enable 2FA. Only use this to tell such admins what is missing.
+	pub async fn authenticate(parts: &Parts, state: &AppState) -> AppResult<Self> {
+		let token = bearer_token(&parts.headers)?;
+
+		if !token.starts_with(API_TOKEN_PREFIX) {
+			return User::from_token(&state.pool, token, &state.secrets.session_expiry).await;
+		}
+		let (mut user, scopes) = ApiToken::authenticate(&state.pool, token).await?;
+		let required = Scope::required_for(&parts.method, parts.uri.path()).ok_or(
+			AppError::forbidden("API tokens cannot be used for this endpoint"),
+		)?;
+		if !scopes.contains(&required) {
+			return Err(AppError::forbidden(format!(
+				"Token is missing the {} scope",
+				required.as_str()
+			)));
+		}
+		// Admin rights need to be granted to the token explicitly, even on non-admin routes
+		user.is_admin &= scopes.contains(&Scope::Admin);
+		Ok(user)
+	}
+
+	/// Whether the user is an admin who cannot use their rights until they enable 2FA
+	pub fn needs_two_factor_for_admin(&self, state: &AppState) -> bool {
+		self.is_admin && state.secrets.require_admin_two_factor && !self.two_factor_enabled
+	}
+
 	pub async fn get_all(pool: &PgPool) -> AppResult<Vec<User>> {
 		sqlx::query_as!(
 			User,
 			r#"
-			SELECT id, username, email, password, is_admin
+			SELECT id, username, email, password, is_admin, verified_at,
+				totp_enabled_at IS NOT NULL AS "two_factor_enabled!"
 			FROM users
-			"#
+			WHERE id <> $1
+			"#,
+			DELETED_USER_ID
 		)
 		.fetch_all(pool)
 		.await
@@ -160,17 +463,12 @@ where
 	type Rejection = AppError;
 
 	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
-		let token = parts
-			.headers
-			.get("Authorization")
-			.ok_or(AppError::unauthorized("Missing authorization header"))?
-			.to_str()
-			.map_err(|_| AppError::unauthorized("Invalid authorization header"))?
-			.trim_start_matches("Bearer ")
-			.to_string();
 		let state = Arc::from_ref(state);
-
-		let user = User::from_token(&state.pool, &token).await?;
+		let mut user = User::authenticate(parts, &state).await?;
+		// Handlers check `is_admin` directly, so withholding the rights here covers all of them
+		if user.needs_two_factor_for_admin(&state) {
+			user.is_admin = false;
+		}
 		Ok(user)
 	}
 }
@@ -0,0 +1,33 @@
+use std::sync::Arc;
+
+use crate::{error::AppError, AppState};
+
+use super::user::User;
+use axum::{
+	async_trait,
+	extract::{FromRef, FromRequestParts},
+	http::request::Parts,
+};
+
+/// A user who has confirmed their email address
+#[derive(Debug, Clone)]
+pub struct VerifiedUser {
+	pub user: User,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for VerifiedUser
+where
+	Arc<AppState>: FromRef<S>,
+	S: Send + Sync,
+{
+	type Rejection = AppError;
+
+	async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+		let user = User::from_request_parts(parts, state).await?;
+		user.verified_at
+			.is_some()
+			.then_some(VerifiedUser { user })
+			.ok_or_else(|| AppError::forbidden("Please verify your email address first"))
+	}
+}
@@ -0,0 +1,60 @@
+use std::{sync::Arc, time::Duration};
+
+use tracing::{info, warn};
+
+use crate::{
+	models::{image::Image, login_failure::LoginFailures, session::Session},
+	AppState,
+};
+
+/// Deletes expired sessions once an hour
+pub fn spawn_session_purge(state: Arc<AppState>) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+		loop {
+			interval.tick().await;
+			match Session::purge_expired(&state.pool, &state.secrets.session_expiry).await {
+				Ok(0) => {}
+				Ok(count) => info!("Purged {} expired sessions", count),
+				Err(e) => warn!("Failed to purge expired sessions: {}", e),
+			}
+		}
+	});
+}
+
+/// Deletes failed logins that no longer count once an hour
+pub fn spawn_login_failure_purge(state: Arc<AppState>) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(Duration::from_secs(60 * 60));
+		loop {
+			interval.tick().await;
+			match LoginFailures::purge_stale(&state.pool).await {
+				Ok(0) => {}
+				Ok(count) => info!("Purged {} stale failed logins", count),
+				Err(e) => warn!("Failed to purge failed logins: {}", e),
+			}
+		}
+	});
+}
+
+/// Deletes abandoned images every six hours
+pub fn spawn_image_gc(state: Arc<AppState>) {
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(Duration::from_secs(6 * 60 * 60));
+		loop {
+			interval.tick().await;
+			match Image::collect_orphans(
+				&state.pool,
+				&*state.images,
+				state.secrets.image_gc_grace_hours,
+				false,
+			)
+			.await
+			{
+				Ok(c) if c.candidates.is_empty() => {}
+				Ok(c) => info!("Deleted {} orphaned images, {} failed", c.deleted, c.failed),
+				Err(e) => warn!("Failed to collect orphaned images: {}", e),
+			}
+		}
+	});
+}